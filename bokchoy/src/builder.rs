@@ -1,22 +1,62 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
+
+use sqlx::PgPool;
 
 use crate::{PaymentService, Provider, psp::PaymentServiceProvider};
 
+#[derive(Debug, thiserror::Error)]
+pub enum BuildError {
+    #[error("provider {0:?} is registered more than once")]
+    DuplicateProvider(Provider),
+}
+
 #[derive(Default)]
 pub struct PaymentServiceBuilder {
     providers: Vec<(Provider, Box<dyn PaymentServiceProvider + Send + Sync>)>,
+    pool: Option<PgPool>,
+    max_callback_body_size: Option<usize>,
 }
 
 impl PaymentServiceBuilder {
-    pub fn register(&mut self, key: Provider, provider: impl PaymentServiceProvider + 'static) {
+    pub fn register(
+        &mut self,
+        key: Provider,
+        provider: impl PaymentServiceProvider + 'static,
+    ) -> &mut Self {
         self.providers.push((key, Box::new(provider)));
+
+        self
     }
 
-    pub fn build(self) -> PaymentService {
-        let providers = self.providers.into_iter().collect();
+    /// 供自行管理事务的方法（如 [`PaymentService::handle_pay_callback_pooled`]）使用，
+    /// 只使用接受 `&mut PgConnection` 的方法时可以不设置
+    pub fn pool(&mut self, pool: PgPool) -> &mut Self {
+        self.pool = Some(pool);
 
-        PaymentService {
-            providers: Arc::new(providers),
+        self
+    }
+
+    /// 回调 body 的最大长度（字节），超出时不做验签和解析，默认 256K
+    pub fn max_callback_body_size(&mut self, bytes: usize) -> &mut Self {
+        self.max_callback_body_size = Some(bytes);
+
+        self
+    }
+
+    /// 同一个 [`Provider`] 被注册多次时返回错误
+    pub fn build(self) -> Result<PaymentService, BuildError> {
+        let mut providers = HashMap::with_capacity(self.providers.len());
+
+        for (key, provider) in self.providers {
+            if providers.insert(key, provider).is_some() {
+                return Err(BuildError::DuplicateProvider(key));
+            }
         }
+
+        Ok(PaymentService {
+            providers: Arc::new(providers),
+            pool: self.pool,
+            max_callback_body_size: self.max_callback_body_size.unwrap_or(256 * 1024),
+        })
     }
 }
@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
 
+use crate::InvalidEnumValue;
+
 #[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct HttpRequestJson {
+pub struct HttpRequestJson {
     url: String,
     method: String,
     headers: Vec<(String, String)>,
@@ -39,7 +41,7 @@ impl HttpRequestJson {
             .filter_map(|(k, v)| v.to_str().map(|v| (k.to_string(), v.to_string())).ok())
             .collect::<Vec<_>>();
 
-        let body = serde_json::from_slice(req.body()).unwrap();
+        let body = body_to_json(req.body());
 
         Self {
             url,
@@ -50,8 +52,18 @@ impl HttpRequestJson {
     }
 }
 
+/// 事件记录是尽力而为的，body 不是 JSON 时按字符串保存，为空时保存为 null
+fn body_to_json(body: &[u8]) -> serde_json::Value {
+    if body.is_empty() {
+        return serde_json::Value::Null;
+    }
+
+    serde_json::from_slice(body)
+        .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct HttpResponseJson {
+pub struct HttpResponseJson {
     pub status: u16,
     pub headers: Vec<(String, String)>,
     pub body: serde_json::Value,
@@ -67,7 +79,7 @@ impl HttpResponseJson {
             .filter_map(|(k, v)| v.to_str().map(|v| (k.to_string(), v.to_string())).ok())
             .collect::<Vec<_>>();
 
-        let body = serde_json::to_value(res.body()).unwrap();
+        let body = serde_json::to_value(res.body()).unwrap_or(serde_json::Value::Null);
 
         Self {
             status,
@@ -77,11 +89,6 @@ impl HttpResponseJson {
     }
 }
 
-pub struct PaymentEvent {
-    http_req: HttpRequestJson,
-    http_res: Option<HttpResponseJson>,
-}
-
 #[repr(i16)]
 #[derive(Clone, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -123,7 +130,21 @@ impl<'r> sqlx::Decode<'r, sqlx::Postgres> for PaymentEventKind {
             1 => Ok(PaymentEventKind::PaymentCallback),
             2 => Ok(PaymentEventKind::PaymentRefund),
             3 => Ok(PaymentEventKind::RefundCallback),
-            _ => Err(format!("Invalid PaymentStatus value: {}", val).into()),
+            _ => Err(InvalidEnumValue::new::<Self>(val).into()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn body_to_json_tolerates_non_json() {
+        assert_eq!(body_to_json(br#"{"a":1}"#), json!({ "a": 1 }));
+        assert_eq!(body_to_json(b"<xml/>"), json!("<xml/>"));
+        assert_eq!(body_to_json(b""), serde_json::Value::Null);
+    }
+}
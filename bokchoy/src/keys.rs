@@ -0,0 +1,115 @@
+use rsa::{
+    RsaPrivateKey, RsaPublicKey,
+    pkcs1::{DecodeRsaPrivateKey as _, DecodeRsaPublicKey as _},
+    pkcs8::{DecodePrivateKey as _, DecodePublicKey as _},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error(transparent)]
+    Pem(#[from] pem::PemError),
+
+    /// PEM 的类型既不是 PKCS#1 也不是 PKCS#8 / SPKI
+    #[error("unsupported pem tag: {0}")]
+    UnsupportedTag(String),
+
+    #[error("invalid rsa key")]
+    InvalidKey,
+}
+
+/// 以 `-----BEGIN` 开头的视为 PEM，否则视为 DER
+fn parse_pem(data: &[u8]) -> Result<Option<pem::Pem>, KeyError> {
+    if !data.trim_ascii_start().starts_with(b"-----BEGIN") {
+        return Ok(None);
+    }
+
+    Ok(Some(pem::parse(data)?))
+}
+
+/// 读取 RSA 私钥，支持 PKCS#1（`RSA PRIVATE KEY`）与 PKCS#8（`PRIVATE KEY`）的 PEM 或 DER
+/// 如微信支付商户证书的 `apiclient_key.pem`
+pub fn load_private_key(pem_or_der: &[u8]) -> Result<RsaPrivateKey, KeyError> {
+    let Some(pem) = parse_pem(pem_or_der)? else {
+        return RsaPrivateKey::from_pkcs8_der(pem_or_der)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_der(pem_or_der))
+            .map_err(|_| KeyError::InvalidKey);
+    };
+
+    match pem.tag() {
+        "RSA PRIVATE KEY" => RsaPrivateKey::from_pkcs1_der(pem.contents()).ok(),
+        "PRIVATE KEY" => RsaPrivateKey::from_pkcs8_der(pem.contents()).ok(),
+        tag => return Err(KeyError::UnsupportedTag(tag.to_string())),
+    }
+    .ok_or(KeyError::InvalidKey)
+}
+
+/// 读取 RSA 公钥，支持 PKCS#1（`RSA PUBLIC KEY`）与 SPKI（`PUBLIC KEY`）的 PEM 或 DER
+/// 如微信支付公钥 `pub_key.pem`
+pub fn load_public_key(pem_or_der: &[u8]) -> Result<RsaPublicKey, KeyError> {
+    let Some(pem) = parse_pem(pem_or_der)? else {
+        return RsaPublicKey::from_public_key_der(pem_or_der)
+            .or_else(|_| RsaPublicKey::from_pkcs1_der(pem_or_der))
+            .map_err(|_| KeyError::InvalidKey);
+    };
+
+    match pem.tag() {
+        "RSA PUBLIC KEY" => RsaPublicKey::from_pkcs1_der(pem.contents()).ok(),
+        "PUBLIC KEY" => RsaPublicKey::from_public_key_der(pem.contents()).ok(),
+        tag => return Err(KeyError::UnsupportedTag(tag.to_string())),
+    }
+    .ok_or(KeyError::InvalidKey)
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::{
+        pkcs1::{EncodeRsaPrivateKey as _, EncodeRsaPublicKey as _, LineEnding},
+        pkcs8::{EncodePrivateKey as _, EncodePublicKey as _},
+    };
+
+    use super::*;
+
+    #[test]
+    fn loads_all_encodings() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let public = key.to_public_key();
+
+        let private_inputs = [
+            key.to_pkcs1_pem(LineEnding::LF)
+                .unwrap()
+                .as_bytes()
+                .to_vec(),
+            key.to_pkcs8_pem(LineEnding::LF)
+                .unwrap()
+                .as_bytes()
+                .to_vec(),
+            key.to_pkcs1_der().unwrap().as_bytes().to_vec(),
+            key.to_pkcs8_der().unwrap().as_bytes().to_vec(),
+        ];
+        for input in private_inputs {
+            assert_eq!(load_private_key(&input).unwrap(), key);
+        }
+
+        let public_inputs = [
+            public.to_pkcs1_pem(LineEnding::LF).unwrap().into_bytes(),
+            public
+                .to_public_key_pem(LineEnding::LF)
+                .unwrap()
+                .into_bytes(),
+            public.to_pkcs1_der().unwrap().into_vec(),
+            public.to_public_key_der().unwrap().into_vec(),
+        ];
+        for input in public_inputs {
+            assert_eq!(load_public_key(&input).unwrap(), public);
+        }
+
+        assert!(matches!(
+            load_private_key(b"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n"),
+            Err(KeyError::UnsupportedTag(_))
+        ));
+        assert!(matches!(
+            load_public_key(b"not a key"),
+            Err(KeyError::InvalidKey)
+        ));
+    }
+}
@@ -1,24 +1,36 @@
 use std::{collections::HashMap, sync::Arc};
 
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 use uuid::Uuid;
 
 use crate::{
     event::PaymentEventKind,
-    psp::{PayRequest, PayResponse, PaymentServiceProvider, RefundRequest, RefundResponse},
+    psp::{
+        CallbackError, PayError, PayRequest, PayResponse, PaymentServiceProvider, RefundError,
+        RefundRequest, RefundResponse,
+    },
 };
 
 mod builder;
 mod event;
+pub mod keys;
 pub mod migration;
 pub mod psp;
+mod query;
 mod utils;
 
+pub use builder::BuildError;
+pub use jichu::InvalidEnumValue;
+pub use query::RefundQuery;
+
 #[repr(i16)]
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 pub enum Provider {
     WxpayJsapi = 0,
     WxpayNative = 1,
+    Alipay = 2,
 }
 
 impl sqlx::Type<sqlx::Postgres> for Provider {
@@ -50,13 +62,15 @@ impl<'r> sqlx::Decode<'r, sqlx::Postgres> for Provider {
         match val {
             0 => Ok(Provider::WxpayJsapi),
             1 => Ok(Provider::WxpayNative),
-            _ => Err(format!("Invalid Provider value: {}", val).into()),
+            2 => Ok(Provider::Alipay),
+            _ => Err(InvalidEnumValue::new::<Self>(val).into()),
         }
     }
 }
 
 #[repr(i16)]
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 #[serde(rename_all = "snake_case")]
 pub enum PaymentStatus {
     Pending = 0,
@@ -65,6 +79,42 @@ pub enum PaymentStatus {
     Refunded = 30,
 }
 
+impl PaymentStatus {
+    /// 与 serde 序列化一致的 snake_case 名称
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Success => "success",
+            PaymentStatus::Failed => "failed",
+            PaymentStatus::Refunded => "refunded",
+        }
+    }
+}
+
+impl std::fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid payment status: {0}")]
+pub struct ParsePaymentStatusError(String);
+
+impl std::str::FromStr for PaymentStatus {
+    type Err = ParsePaymentStatusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(PaymentStatus::Pending),
+            "success" => Ok(PaymentStatus::Success),
+            "failed" => Ok(PaymentStatus::Failed),
+            "refunded" => Ok(PaymentStatus::Refunded),
+            _ => Err(ParsePaymentStatusError(s.to_string())),
+        }
+    }
+}
+
 impl sqlx::Type<sqlx::Postgres> for PaymentStatus {
     fn type_info() -> sqlx::postgres::PgTypeInfo {
         <i16 as sqlx::Type<sqlx::Postgres>>::type_info()
@@ -96,18 +146,57 @@ impl<'r> sqlx::Decode<'r, sqlx::Postgres> for PaymentStatus {
             10 => Ok(PaymentStatus::Success),
             20 => Ok(PaymentStatus::Failed),
             30 => Ok(PaymentStatus::Refunded),
-            _ => Err(format!("Invalid PaymentStatus value: {}", val).into()),
+            _ => Err(InvalidEnumValue::new::<Self>(val).into()),
         }
     }
 }
 
 #[repr(i16)]
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 #[serde(rename_all = "snake_case")]
 pub enum RefundStatus {
     Pending = 0,
     Success = 10,
     Failed = 20,
+    /// 退款异常，退款到银行发现用户的卡作废或者冻结了，需人工介入
+    Abnormal = 30,
+}
+
+impl RefundStatus {
+    /// 与 serde 序列化一致的 snake_case 名称
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefundStatus::Pending => "pending",
+            RefundStatus::Success => "success",
+            RefundStatus::Failed => "failed",
+            RefundStatus::Abnormal => "abnormal",
+        }
+    }
+}
+
+impl std::fmt::Display for RefundStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid refund status: {0}")]
+pub struct ParseRefundStatusError(String);
+
+impl std::str::FromStr for RefundStatus {
+    type Err = ParseRefundStatusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(RefundStatus::Pending),
+            "success" => Ok(RefundStatus::Success),
+            "failed" => Ok(RefundStatus::Failed),
+            "abnormal" => Ok(RefundStatus::Abnormal),
+            _ => Err(ParseRefundStatusError(s.to_string())),
+        }
+    }
 }
 
 impl sqlx::Type<sqlx::Postgres> for RefundStatus {
@@ -140,7 +229,8 @@ impl<'r> sqlx::Decode<'r, sqlx::Postgres> for RefundStatus {
             0 => Ok(RefundStatus::Pending),
             10 => Ok(RefundStatus::Success),
             20 => Ok(RefundStatus::Failed),
-            _ => Err(format!("Invalid RefundStatus value: {}", val).into()),
+            30 => Ok(RefundStatus::Abnormal),
+            _ => Err(InvalidEnumValue::new::<Self>(val).into()),
         }
     }
 }
@@ -156,6 +246,7 @@ pub struct RecordSuccessfulPaymentRequest {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 pub struct PayCallbackResult {
     pub payment_id: Uuid,
     pub biz_id: Uuid,
@@ -163,9 +254,12 @@ pub struct PayCallbackResult {
     pub status: PaymentStatus,
     pub provider_trade_no: String,
     pub success_at: Option<time::OffsetDateTime>,
+    /// 下单时传入的 [`PayRequest::attach`]
+    pub attach: Option<String>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 pub struct RefundCallbackResult {
     pub refund_id: Uuid,
     pub payment_id: Uuid,
@@ -173,10 +267,12 @@ pub struct RefundCallbackResult {
     pub amount: i64,
     pub status: RefundStatus,
     pub provider_refund_no: String,
+    pub reason: Option<String>,
     pub success_at: Option<time::OffsetDateTime>,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 pub struct PaymentRecord {
     pub id: Uuid,
     pub provider_trade_no: Option<String>,
@@ -187,9 +283,27 @@ pub struct PaymentRecord {
     pub status: PaymentStatus,
 }
 
+#[derive(Debug, Clone, sqlx::FromRow)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct RefundRecord {
+    pub id: Uuid,
+    pub payment_id: Uuid,
+    pub biz_id: Uuid,
+    pub provider_refund_no: Option<String>,
+    pub amount: i64,
+    pub reason: Option<String>,
+    pub status: RefundStatus,
+}
+
+/// 各方法均接受 `&mut PgConnection`，可直接使用连接池中的连接。
+///
+/// `pay`、`refund` 及回调处理会写入多条记录，需要保证原子性时，
+/// 调用方应传入事务（`&mut *tx`）。`*_pooled` 方法则在内部使用连接池开启事务。
 #[derive(Clone)]
 pub struct PaymentService {
     providers: Arc<HashMap<Provider, Box<dyn PaymentServiceProvider + Send + Sync>>>,
+    pool: Option<sqlx::PgPool>,
+    max_callback_body_size: usize,
 }
 
 impl std::fmt::Debug for PaymentService {
@@ -203,10 +317,29 @@ impl PaymentService {
         builder::PaymentServiceBuilder::default()
     }
 
+    fn check_callback_body_size(
+        &self,
+        req: &http::Request<bytes::Bytes>,
+    ) -> Result<(), CallbackError> {
+        let size = req.body().len();
+
+        if size > self.max_callback_body_size {
+            return Err(CallbackError::PayloadTooLarge {
+                size,
+                limit: self.max_callback_body_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// `limit` 为 `None` 时不限制条数
     pub async fn get_successful_payments(
         &self,
         biz_id: Uuid,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        limit: Option<i64>,
+        offset: i64,
+        conn: &mut PgConnection,
     ) -> Result<Vec<PaymentRecord>, sqlx::Error> {
         sqlx::query_as::<_, PaymentRecord>(
             r#"
@@ -216,18 +349,60 @@ impl PaymentService {
             FROM bokchoy.payments
             WHERE biz_id = $1 AND status = $2
             ORDER BY created_at DESC
+            LIMIT $3 OFFSET $4
             "#,
         )
         .bind(biz_id)
         .bind(PaymentStatus::Success)
-        .fetch_all(&mut **tx)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// 成功支付的总金额，不扣除退款
+    pub async fn sum_successful_payments(
+        &self,
+        biz_id: Uuid,
+        conn: &mut PgConnection,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT COALESCE(SUM(amount), 0)::int8
+            FROM bokchoy.payments
+            WHERE biz_id = $1 AND status = $2
+            "#,
+        )
+        .bind(biz_id)
+        .bind(PaymentStatus::Success)
+        .fetch_one(&mut *conn)
+        .await
+    }
+
+    pub async fn get_refunds_by_biz_id(
+        &self,
+        biz_id: Uuid,
+        conn: &mut PgConnection,
+    ) -> Result<Vec<RefundRecord>, sqlx::Error> {
+        sqlx::query_as::<_, RefundRecord>(
+            r#"
+            SELECT
+                id, payment_id, biz_id, provider_refund_no,
+                amount, reason, status
+            FROM bokchoy.refunds
+            WHERE biz_id = $1
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(biz_id)
+        .fetch_all(&mut *conn)
         .await
     }
 
     pub async fn record_successful_payment(
         &self,
         req: RecordSuccessfulPaymentRequest,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        conn: &mut PgConnection,
     ) -> Result<(), sqlx::Error> {
         sqlx::query(
             r#"
@@ -250,7 +425,7 @@ impl PaymentService {
         .bind(req.provider)
         .bind(req.provider_trade_no)
         .bind(req.success_at)
-        .execute(&mut **tx)
+        .execute(&mut *conn)
         .await?;
 
         Ok(())
@@ -260,14 +435,24 @@ impl PaymentService {
         &self,
         key: Provider,
         req: PayRequest,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> PayResponse {
-        let provider = self.providers.get(&key).unwrap();
+        conn: &mut PgConnection,
+    ) -> Result<PayResponse, PayError> {
+        let provider = self
+            .providers
+            .get(&key)
+            .ok_or(PayError::UnknownProvider(key))?;
+
+        let idempotency_key = req.idempotency_key.clone();
+        let (amount, biz_id) = (req.amount, req.biz_id);
 
         let id = sqlx::query_scalar::<_, Uuid>(
             r#"
-            INSERT INTO bokchoy.payments (description, status, amount, biz_id, provider)
-            VALUES ($1, $2, $3, $4, $5)
+            INSERT INTO bokchoy.payments (
+                description, status, amount, biz_id, provider, idempotency_key
+            )
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (idempotency_key) WHERE idempotency_key IS NOT NULL AND status <> 20
+            DO NOTHING
             RETURNING id
             "#,
         )
@@ -276,11 +461,80 @@ impl PaymentService {
         .bind(req.amount)
         .bind(req.biz_id)
         .bind(key)
-        .fetch_one(&mut **tx)
-        .await
-        .unwrap();
+        .bind(&idempotency_key)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        // 幂等键冲突，已支付时拒绝重复下单，下单参数一致时返回已有待支付支付单的支付参数
+        let Some(id) = id else {
+            let idempotency_key = idempotency_key.expect("conflict requires an idempotency key");
 
-        let (res, http_req, http_res) = provider.pay(id, req).await;
+            let existing = sqlx::query_as::<
+                _,
+                (
+                    PaymentStatus,
+                    Option<serde_json::Value>,
+                    Option<String>,
+                    i64,
+                    Uuid,
+                    Provider,
+                ),
+            >(
+                r#"
+                SELECT status, provider_params, prepay_id, amount, biz_id, provider
+                FROM bokchoy.payments
+                WHERE idempotency_key = $1 AND status <> $2
+                "#,
+            )
+            .bind(&idempotency_key)
+            .bind(PaymentStatus::Failed)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+            return match existing {
+                Some((PaymentStatus::Success | PaymentStatus::Refunded, ..)) => {
+                    Err(PayError::OrderPaid)
+                }
+                Some((_, _, _, existing_amount, existing_biz_id, existing_key))
+                    if (existing_amount, existing_biz_id, existing_key)
+                        != (amount, biz_id, key) =>
+                {
+                    Err(PayError::IdempotencyKeyMismatch(idempotency_key))
+                }
+                Some((_, Some(provider_params), prepay_id, ..)) => Ok(PayResponse {
+                    provider_params,
+                    prepay_id,
+                }),
+                _ => Err(PayError::IdempotencyKeyInFlight(idempotency_key)),
+            };
+        };
+
+        let (res, http_req, http_res) = match provider.pay(id, req).await {
+            Ok(res) => res,
+            Err(e) => {
+                // 下单失败时释放幂等键，允许重试；已支付的订单仍等待回调或查询
+                if idempotency_key.is_some() && !matches!(e, PayError::OrderPaid) {
+                    sqlx::query(
+                        "UPDATE bokchoy.payments SET status = $2, updated_at = now() WHERE id = $1",
+                    )
+                    .bind(id)
+                    .bind(PaymentStatus::Failed)
+                    .execute(&mut *conn)
+                    .await?;
+                }
+
+                return Err(e);
+            }
+        };
+
+        sqlx::query(
+            "UPDATE bokchoy.payments SET provider_params = $2, prepay_id = $3 WHERE id = $1",
+        )
+        .bind(id)
+        .bind(&res.provider_params)
+        .bind(&res.prepay_id)
+        .execute(&mut *conn)
+        .await?;
 
         sqlx::query(
             r#"
@@ -290,24 +544,28 @@ impl PaymentService {
         )
         .bind(id)
         .bind(PaymentEventKind::PaymentCreate)
-        .bind(serde_json::to_value(&http_req).unwrap())
-        .bind(http_res.map(|j| serde_json::to_value(&j).unwrap()))
-        .execute(&mut **tx)
-        .await
-        .unwrap();
+        .bind(sqlx::types::Json(&http_req))
+        .bind(http_res.as_ref().map(sqlx::types::Json))
+        .execute(&mut *conn)
+        .await?;
 
-        res
+        Ok(res)
     }
 
     pub async fn handle_pay_callback(
         &self,
         key: Provider,
         req: http::Request<bytes::Bytes>,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> (PayCallbackResult, http::Response<String>) {
-        let provider = self.providers.get(&key).unwrap();
+        conn: &mut PgConnection,
+    ) -> Result<(PayCallbackResult, http::Response<String>), CallbackError> {
+        let provider = self
+            .providers
+            .get(&key)
+            .ok_or(CallbackError::UnknownProvider(key))?;
 
-        let (outcome, http_req, http_res) = provider.pay_callback(req).await;
+        self.check_callback_body_size(&req)?;
+
+        let (outcome, http_req, http_res) = provider.pay_callback(req).await?;
 
         let (biz_id, amount) = sqlx::query_as::<_, (Uuid, i64)>(
             r#"
@@ -323,9 +581,8 @@ impl PaymentService {
         .bind(PaymentStatus::Success)
         .bind(outcome.provider_trade_no.clone())
         .bind(outcome.success_at)
-        .fetch_one(&mut **tx)
-        .await
-        .unwrap();
+        .fetch_one(&mut *conn)
+        .await?;
 
         sqlx::query(
             r#"
@@ -335,13 +592,12 @@ impl PaymentService {
         )
         .bind(outcome.id)
         .bind(PaymentEventKind::PaymentCallback)
-        .bind(serde_json::to_value(&http_req).unwrap())
-        .bind(http_res.map(|j| serde_json::to_value(&j).unwrap()))
-        .execute(&mut **tx)
-        .await
-        .unwrap();
+        .bind(sqlx::types::Json(&http_req))
+        .bind(http_res.as_ref().map(sqlx::types::Json))
+        .execute(&mut *conn)
+        .await?;
 
-        (
+        Ok((
             PayCallbackResult {
                 payment_id: outcome.id,
                 biz_id,
@@ -349,41 +605,77 @@ impl PaymentService {
                 status: PaymentStatus::Success,
                 provider_trade_no: outcome.provider_trade_no,
                 success_at: Some(outcome.success_at),
+                attach: outcome.attach,
             },
             outcome.res,
-        )
+        ))
+    }
+
+    /// 与 [`PaymentService::handle_pay_callback`] 相同，在内部开启并提交事务
+    /// 构建时未设置连接池时返回 [`CallbackError::MissingPool`]
+    pub async fn handle_pay_callback_pooled(
+        &self,
+        key: Provider,
+        req: http::Request<bytes::Bytes>,
+    ) -> Result<(PayCallbackResult, http::Response<String>), CallbackError> {
+        let pool = self.pool.as_ref().ok_or(CallbackError::MissingPool)?;
+
+        let mut tx = pool.begin().await?;
+
+        let res = self.handle_pay_callback(key, req, &mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(res)
     }
 
     pub async fn handle_refund_callback(
         &self,
         key: Provider,
         req: http::Request<bytes::Bytes>,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> (RefundCallbackResult, http::Response<String>) {
-        let provider = self.providers.get(&key).unwrap();
+        conn: &mut PgConnection,
+    ) -> Result<(RefundCallbackResult, http::Response<String>), CallbackError> {
+        let provider = self
+            .providers
+            .get(&key)
+            .ok_or(CallbackError::UnknownProvider(key))?;
+
+        self.check_callback_body_size(&req)?;
 
-        let (outcome, http_req, http_res) = provider.refund_callback(req).await;
+        let (outcome, http_req, http_res) = provider.refund_callback(req).await?;
 
-        let (payment_id, amount, provider_refund_no, biz_id) =
-            sqlx::query_as::<_, (Uuid, i64, Option<String>, Uuid)>(
+        // 同步返回成功的退款（如支付宝）或重复投递的回调不能再次累加已退款金额
+        let (payment_id, amount, provider_refund_no, biz_id, reason, old_status) =
+            sqlx::query_as::<
+                _,
+                (
+                    Uuid,
+                    i64,
+                    Option<String>,
+                    Uuid,
+                    Option<String>,
+                    RefundStatus,
+                ),
+            >(
                 r#"
                 UPDATE bokchoy.refunds r
                 SET
                     status = $2,
                     success_at = $3,
                     updated_at = now()
-                FROM bokchoy.payments p
-                WHERE r.id = $1 AND r.payment_id = p.id
-                RETURNING r.payment_id, r.amount, r.provider_refund_no, p.biz_id
+                FROM (SELECT id, status FROM bokchoy.refunds WHERE id = $1 FOR UPDATE) old
+                WHERE r.id = old.id
+                RETURNING
+                    r.payment_id, r.amount, r.provider_refund_no, r.biz_id, r.reason,
+                    old.status
                 "#,
             )
             .bind(outcome.refund_id)
             .bind(outcome.status)
             .bind(outcome.success_at)
-            .fetch_one(&mut **tx)
-            .await
-            .unwrap();
-        if outcome.status == RefundStatus::Success {
+            .fetch_one(&mut *conn)
+            .await?;
+        if outcome.status == RefundStatus::Success && old_status != RefundStatus::Success {
             sqlx::query(
                 r#"
                 UPDATE bokchoy.payments
@@ -396,9 +688,8 @@ impl PaymentService {
             .bind(payment_id)
             .bind(amount)
             .bind(outcome.success_at)
-            .execute(&mut **tx)
-            .await
-            .unwrap();
+            .execute(&mut *conn)
+            .await?;
         }
 
         sqlx::query(
@@ -409,13 +700,12 @@ impl PaymentService {
         )
         .bind(payment_id)
         .bind(PaymentEventKind::RefundCallback)
-        .bind(serde_json::to_value(&http_req).unwrap())
-        .bind(http_res.map(|j| serde_json::to_value(&j).unwrap()))
-        .execute(&mut **tx)
-        .await
-        .unwrap();
+        .bind(sqlx::types::Json(&http_req))
+        .bind(http_res.as_ref().map(sqlx::types::Json))
+        .execute(&mut *conn)
+        .await?;
 
-        (
+        Ok((
             RefundCallbackResult {
                 refund_id: outcome.refund_id,
                 payment_id,
@@ -423,62 +713,120 @@ impl PaymentService {
                 amount,
                 status: outcome.status,
                 provider_refund_no: provider_refund_no.unwrap_or_default(),
+                reason,
                 success_at: outcome.success_at,
             },
             outcome.res,
+        ))
+    }
+
+    /// 退还支付单剩余的全部金额，处理中的退款也会被扣除
+    /// 没有可退金额时返回 [`RefundError::NothingToRefund`]
+    pub async fn refund_full(
+        &self,
+        payment_id: Uuid,
+        reason: Option<String>,
+        notify_url: Option<String>,
+        conn: &mut PgConnection,
+    ) -> Result<RefundResponse, RefundError> {
+        let remaining = sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT p.amount - p.refunded_amount - COALESCE((
+                SELECT SUM(r.amount) FROM bokchoy.refunds r
+                WHERE r.payment_id = p.id AND r.status = $2
+            ), 0)::int8
+            FROM bokchoy.payments p
+            WHERE p.id = $1
+            FOR UPDATE
+            "#,
         )
+        .bind(payment_id)
+        .bind(RefundStatus::Pending)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if remaining <= 0 {
+            return Err(RefundError::NothingToRefund(payment_id));
+        }
+
+        self.refund(payment_id, remaining, reason, notify_url, conn)
+            .await
     }
 
+    /// `notify_url` 覆盖渠道配置的退款回调地址，见 [`RefundRequest::notify_url`]
     pub async fn refund(
         &self,
         payment_id: Uuid,
         amount: i64,
         reason: Option<String>,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> RefundResponse {
-        let (provider_key, total, provider_trade_no) =
-            sqlx::query_as::<_, (Provider, i64, Option<String>)>(
+        notify_url: Option<String>,
+        conn: &mut PgConnection,
+    ) -> Result<RefundResponse, RefundError> {
+        let (provider_key, total, provider_trade_no, biz_id) =
+            sqlx::query_as::<_, (Provider, i64, Option<String>, Uuid)>(
                 r#"
-                SELECT provider, amount, provider_trade_no
+                SELECT provider, amount, provider_trade_no, biz_id
                 FROM bokchoy.payments
                 WHERE id = $1
                 "#,
             )
             .bind(payment_id)
-            .fetch_one(&mut **tx)
-            .await
-            .unwrap();
+            .fetch_one(&mut *conn)
+            .await?;
 
-        let provider = self.providers.get(&provider_key).unwrap();
+        let provider = self
+            .providers
+            .get(&provider_key)
+            .ok_or(RefundError::UnknownProvider(provider_key))?;
 
         let refund_id = Uuid::now_v7();
 
         let req = RefundRequest {
             refund_id,
-            provider_trade_no: provider_trade_no.expect("Payment missing provider_trade_no"),
+            provider_trade_no: provider_trade_no.ok_or(RefundError::MissingTradeNo(payment_id))?,
             amount,
             total,
+            notify_url,
         };
 
         sqlx::query(
             r#"
             INSERT INTO bokchoy.refunds (
-                id, payment_id,
+                id, payment_id, biz_id,
                 amount, reason, status, created_at, updated_at
             )
-            VALUES ($1, $2, $3, $4, $5, now(), now())
+            VALUES ($1, $2, $3, $4, $5, $6, now(), now())
             "#,
         )
         .bind(refund_id)
         .bind(payment_id)
+        .bind(biz_id)
         .bind(amount)
         .bind(reason)
         .bind(RefundStatus::Pending)
-        .execute(&mut **tx)
-        .await
-        .unwrap();
+        .execute(&mut *conn)
+        .await?;
 
-        let (res, http_req, http_res) = provider.refund(payment_id, req).await;
+        let (res, http_req, http_res) = match provider.refund(payment_id, req).await {
+            Ok(res) => res,
+            Err(e) => {
+                // 渠道明确拒绝时退款不会发生，网络错误时结果未知，保持 Pending
+                if matches!(
+                    e,
+                    RefundError::WxPay(_) | RefundError::Alipay(_) | RefundError::Provider { .. }
+                ) {
+                    sqlx::query(
+                        "UPDATE bokchoy.refunds SET status = $2, updated_at = now() WHERE id = $1",
+                    )
+                    .bind(refund_id)
+                    .bind(RefundStatus::Failed)
+                    .execute(&mut *conn)
+                    .await?;
+                }
+
+                return Err(e);
+            }
+        };
 
         let status = if res.status == "SUCCESS" {
             RefundStatus::Success
@@ -498,9 +846,8 @@ impl PaymentService {
             )
             .bind(payment_id)
             .bind(amount)
-            .execute(&mut **tx)
-            .await
-            .unwrap();
+            .execute(&mut *conn)
+            .await?;
 
             sqlx::query(
                 r#"
@@ -515,9 +862,8 @@ impl PaymentService {
             .bind(refund_id)
             .bind(&res.provider_refund_no)
             .bind(status)
-            .execute(&mut **tx)
-            .await
-            .unwrap();
+            .execute(&mut *conn)
+            .await?;
         } else {
             sqlx::query(
                 r#"
@@ -529,9 +875,8 @@ impl PaymentService {
             .bind(refund_id)
             .bind(&res.provider_refund_no)
             .bind(status)
-            .execute(&mut **tx)
-            .await
-            .unwrap();
+            .execute(&mut *conn)
+            .await?;
         }
 
         sqlx::query(
@@ -542,12 +887,208 @@ impl PaymentService {
         )
         .bind(payment_id)
         .bind(PaymentEventKind::PaymentRefund)
-        .bind(serde_json::to_value(&http_req).unwrap())
-        .bind(http_res.map(|j| serde_json::to_value(&j).unwrap()))
-        .execute(&mut **tx)
+        .bind(sqlx::types::Json(&http_req))
+        .bind(http_res.as_ref().map(sqlx::types::Json))
+        .execute(&mut *conn)
         .await
         .unwrap();
 
-        res
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 连接 `DATABASE_URL` 指向的数据库并执行迁移，以 `cargo test -- --ignored` 运行
+    async fn test_pool() -> sqlx::PgPool {
+        use sea_orm_migration::{MigratorTrait, sea_orm::SqlxPostgresConnector};
+
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is not set");
+        let pool = sqlx::PgPool::connect(&url).await.unwrap();
+
+        let db = SqlxPostgresConnector::from_sqlx_postgres_pool(pool.clone());
+        migration::Migrator::up(&db, None).await.unwrap();
+
+        pool
+    }
+
+    /// 只处理一次下单请求的支付宝网关，响应以 `key` 签名，返回网关地址
+    async fn mock_alipay_gateway(key: &rsa::RsaPrivateKey) -> String {
+        use rsa::signature::{SignatureEncoding as _, Signer as _};
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let response = r#"{"code":"10000","msg":"Success","out_trade_no":"1","trade_no":"2024010122001400000000000001"}"#;
+
+        use base64::prelude::*;
+        let sign = BASE64_STANDARD.encode(
+            rsa::pkcs1v15::SigningKey::<sha2::Sha256>::new(key.clone())
+                .sign(response.as_bytes())
+                .to_bytes(),
+        );
+        let body = format!(r#"{{"alipay_trade_create_response":{response},"sign":"{sign}"}}"#);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0; 64 * 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(res.as_bytes()).await.unwrap();
+        });
+
+        format!("http://{addr}/gateway.do")
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn pay_rejects_idempotency_key_reused_for_another_amount() {
+        let pool = test_pool().await;
+
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let alipay = psp::Alipay::new(
+            "2021000000000000".into(),
+            "https://example.com/alipay".into(),
+            key.clone(),
+            key.to_public_key(),
+        )
+        .gateway_url(mock_alipay_gateway(&key).await);
+
+        let mut builder = PaymentService::builder();
+        builder
+            .pool(pool.clone())
+            .register(Provider::Alipay, alipay);
+        let service = builder.build().unwrap();
+
+        let biz_id = Uuid::now_v7();
+        let idempotency_key = Uuid::now_v7().to_string();
+        let req = |amount| PayRequest {
+            biz_id,
+            amount,
+            description: "test".into(),
+            extras: [("buyer_open_id".to_string(), "open_id".to_string())].into(),
+            idempotency_key: Some(idempotency_key.clone()),
+            attach: None,
+            goods_detail: vec![],
+            notify_url: None,
+        };
+
+        let mut tx = pool.begin().await.unwrap();
+
+        let first = service
+            .pay(Provider::Alipay, req(100), &mut tx)
+            .await
+            .unwrap();
+
+        // 网关只处理一次请求，重复下单必须直接返回已有的支付参数
+        let repeated = service
+            .pay(Provider::Alipay, req(100), &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(repeated.provider_params, first.provider_params);
+
+        let err = service
+            .pay(Provider::Alipay, req(200), &mut tx)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, PayError::IdempotencyKeyMismatch(k) if k == idempotency_key));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn pay_rejects_idempotency_key_of_paid_payment() {
+        let pool = test_pool().await;
+
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let alipay = psp::Alipay::new(
+            "2021000000000000".into(),
+            "https://example.com/alipay".into(),
+            key.clone(),
+            key.to_public_key(),
+        )
+        .gateway_url(mock_alipay_gateway(&key).await);
+
+        let mut builder = PaymentService::builder();
+        builder
+            .pool(pool.clone())
+            .register(Provider::Alipay, alipay);
+        let service = builder.build().unwrap();
+
+        let idempotency_key = Uuid::now_v7().to_string();
+        let req = || PayRequest {
+            biz_id: Uuid::nil(),
+            amount: 100,
+            description: "test".into(),
+            extras: [("buyer_open_id".to_string(), "open_id".to_string())].into(),
+            idempotency_key: Some(idempotency_key.clone()),
+            attach: None,
+            goods_detail: vec![],
+            notify_url: None,
+        };
+
+        let mut tx = pool.begin().await.unwrap();
+
+        service.pay(Provider::Alipay, req(), &mut tx).await.unwrap();
+
+        sqlx::query("UPDATE bokchoy.payments SET status = $2 WHERE idempotency_key = $1")
+            .bind(&idempotency_key)
+            .bind(PaymentStatus::Success)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        // 网关只处理一次请求，重放已支付的幂等键不能再次下单
+        let err = service
+            .pay(Provider::Alipay, req(), &mut tx)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, PayError::OrderPaid));
+    }
+
+    #[tokio::test]
+    async fn pooled_callback_requires_pool() {
+        let service = PaymentService::builder().build().unwrap();
+
+        let req = http::Request::builder().body(bytes::Bytes::new()).unwrap();
+        let err = service
+            .handle_pay_callback_pooled(Provider::Alipay, req)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, CallbackError::MissingPool));
+    }
+
+    #[test]
+    fn status_str_matches_serde() {
+        use PaymentStatus::*;
+
+        for status in [Pending, Success, Failed, Refunded] {
+            let json = serde_json::to_string(&status).unwrap();
+            assert_eq!(json, format!("\"{status}\""));
+            assert_eq!(status.as_str().parse::<PaymentStatus>().unwrap(), status);
+        }
+
+        for status in [
+            RefundStatus::Pending,
+            RefundStatus::Success,
+            RefundStatus::Failed,
+            RefundStatus::Abnormal,
+        ] {
+            let json = serde_json::to_string(&status).unwrap();
+            assert_eq!(json, format!("\"{status}\""));
+            assert_eq!(status.as_str().parse::<RefundStatus>().unwrap(), status);
+        }
+
+        assert!("Success".parse::<PaymentStatus>().is_err());
     }
 }
@@ -0,0 +1,38 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("ALTER TABLE bokchoy.refunds ADD COLUMN biz_id uuid")
+            .await?;
+
+        db.execute_unprepared(
+            r#"
+            UPDATE bokchoy.refunds r
+            SET biz_id = p.biz_id
+            FROM bokchoy.payments p
+            WHERE r.payment_id = p.id
+            "#,
+        )
+        .await?;
+
+        db.execute_unprepared("ALTER TABLE bokchoy.refunds ALTER COLUMN biz_id SET NOT NULL")
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("ALTER TABLE bokchoy.refunds DROP COLUMN biz_id")
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("CREATE INDEX payments_biz_id_idx ON bokchoy.payments (biz_id)")
+            .await?;
+
+        db.execute_unprepared("CREATE INDEX payments_status_idx ON bokchoy.payments (status)")
+            .await?;
+
+        db.execute_unprepared(
+            "CREATE INDEX payment_events_payment_id_idx ON bokchoy.payment_events (payment_id)",
+        )
+        .await?;
+
+        db.execute_unprepared(
+            "CREATE INDEX refunds_payment_id_idx ON bokchoy.refunds (payment_id)",
+        )
+        .await?;
+
+        db.execute_unprepared("CREATE INDEX refunds_biz_id_idx ON bokchoy.refunds (biz_id)")
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("DROP INDEX bokchoy.refunds_biz_id_idx")
+            .await?;
+
+        db.execute_unprepared("DROP INDEX bokchoy.refunds_payment_id_idx")
+            .await?;
+
+        db.execute_unprepared("DROP INDEX bokchoy.payment_events_payment_id_idx")
+            .await?;
+
+        db.execute_unprepared("DROP INDEX bokchoy.payments_status_idx")
+            .await?;
+
+        db.execute_unprepared("DROP INDEX bokchoy.payments_biz_id_idx")
+            .await?;
+
+        Ok(())
+    }
+}
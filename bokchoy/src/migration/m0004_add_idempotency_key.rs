@@ -0,0 +1,52 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared(
+            r#"
+            ALTER TABLE bokchoy.payments
+                ADD COLUMN idempotency_key text,
+                ADD COLUMN provider_params jsonb,
+                ADD COLUMN prepay_id text
+            "#,
+        )
+        .await?;
+
+        // 同一个幂等键只允许存在一笔未失败的支付单，已支付的幂等键不能再次下单；
+        // 下单失败（status = 20）的支付单释放幂等键，允许重试
+        db.execute_unprepared(
+            r#"
+            CREATE UNIQUE INDEX payments_idempotency_key_idx ON bokchoy.payments (idempotency_key)
+            WHERE idempotency_key IS NOT NULL AND status <> 20
+            "#,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("DROP INDEX bokchoy.payments_idempotency_key_idx")
+            .await?;
+
+        db.execute_unprepared(
+            r#"
+            ALTER TABLE bokchoy.payments
+                DROP COLUMN idempotency_key,
+                DROP COLUMN provider_params,
+                DROP COLUMN prepay_id
+            "#,
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 use sea_orm_migration::prelude::*;
 
 mod m0001_create_table;
+mod m0002_add_refund_biz_id;
+mod m0003_create_index;
+mod m0004_add_idempotency_key;
 
 pub struct Migrator;
 
@@ -10,6 +13,11 @@ impl MigratorTrait for Migrator {
     }
 
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m0001_create_table::Migration)]
+        vec![
+            Box::new(m0001_create_table::Migration),
+            Box::new(m0002_add_refund_biz_id::Migration),
+            Box::new(m0003_create_index::Migration),
+            Box::new(m0004_add_idempotency_key::Migration),
+        ]
     }
 }
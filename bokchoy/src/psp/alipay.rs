@@ -0,0 +1,919 @@
+use std::collections::BTreeMap;
+
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use rsa::pkcs1v15::{SigningKey, VerifyingKey};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset, format_description::BorrowedFormatItem};
+use uuid::Uuid;
+
+use crate::{
+    RefundStatus,
+    event::{HttpRequestJson, HttpResponseJson},
+    psp::{
+        CallbackError, PayCallbackOutcome, PayError, PayRequest, PayResponse,
+        PaymentServiceProvider, RefundCallbackOutcome, RefundError, RefundRequest, RefundResponse,
+    },
+    utils::{alipay_sign, alipay_verify, alipay_verify_content},
+};
+
+/// 支付宝接口返回的业务错误，`code` 不为 `10000` 时返回
+#[derive(Debug, Clone, Deserialize, thiserror::Error)]
+#[error("alipay api error {code} {sub_code:?}: {msg} {sub_msg:?}")]
+pub struct AlipayApiError {
+    pub code: String,
+    pub msg: String,
+    pub sub_code: Option<String>,
+    pub sub_msg: Option<String>,
+}
+
+/// 支付宝接口的时间格式，均为北京时间；退款通知中的时间带有毫秒
+const DATETIME: &[BorrowedFormatItem<'_>] =
+    time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+const DATETIME_PARSE: &[BorrowedFormatItem<'_>] = time::macros::format_description!(
+    "[year]-[month]-[day] [hour]:[minute]:[second][optional [.[subsecond]]]"
+);
+const BEIJING: UtcOffset = time::macros::offset!(+8);
+
+/// 支付宝小程序支付（`alipay.trade.create`，`JSAPI_PAY`），使用 RSA2 签名的开放平台网关接口
+///
+/// 支付宝的支付与退款异步通知都发送到下单时的 `notify_url`，
+/// 接收通知时可用 [`Alipay::is_refund_notification`] 区分后分别交给支付或退款回调处理
+#[derive(Debug)]
+pub struct Alipay {
+    app_id: String,
+    notify_url: String,
+    /// 由应用私钥预先构建，避免每次请求复制私钥
+    signing_key: SigningKey<Sha256>,
+    verifying_key: VerifyingKey<Sha256>,
+    gateway_url: String,
+    reqwest: reqwest::Client,
+}
+
+impl Alipay {
+    pub fn new(
+        app_id: String,
+        notify_url: String,
+        app_private_key: rsa::RsaPrivateKey,
+        alipay_public_key: rsa::RsaPublicKey,
+    ) -> Self {
+        Self {
+            app_id,
+            notify_url,
+            signing_key: SigningKey::new(app_private_key),
+            verifying_key: VerifyingKey::new(alipay_public_key),
+            gateway_url: "https://openapi.alipay.com/gateway.do".to_string(),
+            reqwest: reqwest::Client::new(),
+        }
+    }
+
+    /// 网关地址，默认为 `https://openapi.alipay.com/gateway.do`，测试时可指向沙箱或 mock 服务
+    pub fn gateway_url(mut self, url: String) -> Self {
+        self.gateway_url = url;
+
+        self
+    }
+
+    /// 异步通知中带有 `out_biz_no`（退款请求号）时为退款通知
+    pub fn is_refund_notification(body: &[u8]) -> bool {
+        form_urlencoded::parse(body).any(|(k, v)| k == "out_biz_no" && !v.is_empty())
+    }
+
+    /// 调用网关接口，返回请求记录、响应记录与 `{method}_response` 中的业务数据
+    /// 响应带有 `sign` 时校验签名；成功响应（`code` 为 `10000`）必须带有签名，
+    /// 部分网关错误（如 app_id 不存在）的响应不带签名，此时只作为错误返回
+    async fn execute(
+        &self,
+        method: &str,
+        biz_content: serde_json::Value,
+        notify_url: Option<&str>,
+    ) -> Result<(HttpRequestJson, HttpResponseJson, serde_json::Value), ApiError> {
+        let timestamp = OffsetDateTime::now_utc()
+            .to_offset(BEIJING)
+            .format(DATETIME)
+            .expect("format timestamp");
+
+        let mut params = BTreeMap::from(
+            [
+                ("app_id", self.app_id.as_str()),
+                ("method", method),
+                ("format", "JSON"),
+                ("charset", "utf-8"),
+                ("sign_type", "RSA2"),
+                ("timestamp", &timestamp),
+                ("version", "1.0"),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        if let Some(notify_url) = notify_url {
+            params.insert("notify_url".into(), notify_url.to_string());
+        }
+        params.insert("biz_content".into(), biz_content.to_string());
+
+        let sign = alipay_sign(&self.signing_key, &params);
+        params.insert("sign".into(), sign);
+
+        let req = self
+            .reqwest
+            .post(&self.gateway_url)
+            .form(&params)
+            .header("User-Agent", "bokchoy")
+            .header("Accept", "application/json")
+            .build()?;
+
+        let http_req = HttpRequestJson::from_reqwest_req(&req, json!(params));
+
+        let res = self
+            .reqwest
+            .execute(req)
+            .await
+            .inspect_err(|e| tracing::error!(error = %e, method, "alipay request failed"))?;
+
+        let status = res.status().as_u16();
+
+        if !res.status().is_success() {
+            let body = res.text().await?;
+
+            tracing::error!(status, body, method, "alipay request rejected");
+
+            return Err(ApiError::Provider { status, body });
+        }
+
+        let headers = res
+            .headers()
+            .iter()
+            .filter_map(|(k, v)| v.to_str().map(|v| (k.to_string(), v.to_string())).ok())
+            .collect::<Vec<_>>();
+
+        let text = res.text().await?;
+
+        // 签名覆盖的是响应中 `{method}_response` 的原始文本，需保留原样而不是重新序列化
+        let Ok(raw) = serde_json::from_str::<BTreeMap<String, &serde_json::value::RawValue>>(&text)
+        else {
+            tracing::error!(body = text, method, "alipay response is not a json object");
+
+            return Err(ApiError::Provider { status, body: text });
+        };
+
+        let http_res = HttpResponseJson {
+            status,
+            headers,
+            body: serde_json::from_str(&text).unwrap_or_default(),
+        };
+
+        let response_key = format!("{}_response", method.replace('.', "_"));
+        let response_raw = raw.get(&response_key).map_or("null", |r| r.get());
+        let response = serde_json::from_str::<serde_json::Value>(response_raw).unwrap_or_default();
+
+        let sign = raw
+            .get("sign")
+            .and_then(|s| serde_json::from_str::<String>(s.get()).ok());
+
+        match sign {
+            Some(sign) => {
+                alipay_verify_content(&self.verifying_key, response_raw, &sign).map_err(|_| {
+                    tracing::error!(body = text, method, "alipay response signature is invalid");
+
+                    ApiError::InvalidSignature
+                })?
+            }
+            None if response["code"] == "10000" => {
+                tracing::error!(body = text, method, "alipay response is not signed");
+
+                return Err(ApiError::InvalidSignature);
+            }
+            None => {}
+        }
+
+        if response["code"] != "10000" {
+            tracing::error!(body = %http_res.body, method, "alipay request returned an error");
+
+            return Err(match serde_json::from_value::<AlipayApiError>(response) {
+                Ok(err) => ApiError::Alipay(err),
+                Err(_) => ApiError::Provider {
+                    status,
+                    body: http_res.body.to_string(),
+                },
+            });
+        }
+
+        Ok((http_req, http_res, response))
+    }
+
+    /// 解析异步通知并校验签名与 app_id
+    fn verify_notification(
+        &self,
+        req: &http::Request<bytes::Bytes>,
+    ) -> Result<BTreeMap<String, String>, CallbackError> {
+        let params = form_urlencoded::parse(req.body())
+            .into_owned()
+            .collect::<BTreeMap<_, _>>();
+
+        if params.get("sign_type").map(String::as_str) != Some("RSA2") {
+            return Err(CallbackError::InvalidSignature);
+        }
+
+        alipay_verify(&self.verifying_key, &params).map_err(|_| CallbackError::InvalidSignature)?;
+
+        let app_id = field(&params, "app_id")?;
+        if app_id != self.app_id {
+            return Err(CallbackError::AppIdMismatch(app_id.to_string()));
+        }
+
+        Ok(params)
+    }
+}
+
+/// 网关调用的错误，按调用的接口转换为 [`PayError`] 或 [`RefundError`]
+enum ApiError {
+    Http(reqwest::Error),
+    Provider { status: u16, body: String },
+    Alipay(AlipayApiError),
+    InvalidSignature,
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        ApiError::Http(e)
+    }
+}
+
+/// 重复下单时支付宝返回的错误码映射为与微信支付相同的错误
+impl From<ApiError> for PayError {
+    fn from(e: ApiError) -> Self {
+        match e {
+            ApiError::Http(e) => PayError::Http(e),
+            ApiError::Provider { status, body } => PayError::Provider { status, body },
+            ApiError::InvalidSignature => PayError::InvalidResponseSignature,
+            ApiError::Alipay(err) => match err.sub_code.as_deref() {
+                Some("ACQ.TRADE_HAS_SUCCESS") => PayError::OrderPaid,
+                Some("ACQ.TRADE_HAS_CLOSE") => PayError::OrderClosed,
+                Some("ACQ.CONTEXT_INCONSISTENT") => PayError::OutTradeNoUsed,
+                _ => PayError::Alipay(err),
+            },
+        }
+    }
+}
+
+impl From<ApiError> for RefundError {
+    fn from(e: ApiError) -> Self {
+        match e {
+            ApiError::Http(e) => RefundError::Http(e),
+            ApiError::Provider { status, body } => RefundError::Provider { status, body },
+            ApiError::InvalidSignature => RefundError::InvalidResponseSignature,
+            ApiError::Alipay(err) => RefundError::Alipay(err),
+        }
+    }
+}
+
+fn field<'a>(
+    params: &'a BTreeMap<String, String>,
+    name: &'static str,
+) -> Result<&'a str, CallbackError> {
+    params
+        .get(name)
+        .map(String::as_str)
+        .filter(|v| !v.is_empty())
+        .ok_or(CallbackError::InvalidField(name))
+}
+
+fn uuid_field(
+    params: &BTreeMap<String, String>,
+    name: &'static str,
+) -> Result<Uuid, CallbackError> {
+    Uuid::parse_str(field(params, name)?).map_err(|_| CallbackError::InvalidField(name))
+}
+
+fn datetime_field(
+    params: &BTreeMap<String, String>,
+    name: &'static str,
+) -> Result<OffsetDateTime, CallbackError> {
+    PrimitiveDateTime::parse(field(params, name)?, DATETIME_PARSE)
+        .map(|t| t.assume_offset(BEIJING))
+        .map_err(|_| CallbackError::InvalidField(name))
+}
+
+/// 支付宝金额以元为单位，保留两位小数
+fn yuan(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    let cents = cents.unsigned_abs();
+
+    format!("{sign}{}.{:02}", cents / 100, cents % 100)
+}
+
+/// 解析以元为单位、最多两位小数的金额，返回分
+fn parse_yuan(yuan: &str) -> Option<i64> {
+    let (integer, fraction) = yuan.split_once('.').unwrap_or((yuan, ""));
+    if integer.is_empty()
+        || fraction.len() > 2
+        || !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let fraction = format!("{fraction:0<2}");
+
+    integer
+        .parse::<i64>()
+        .ok()?
+        .checked_mul(100)?
+        .checked_add(fraction.parse().ok()?)
+}
+
+/// 通知处理成功时支付宝要求返回纯文本 `success`，否则会重试
+fn success_response() -> http::Response<String> {
+    http::Response::builder()
+        .header("Content-Type", "text/plain")
+        .body("success".to_string())
+        .unwrap()
+}
+
+#[async_trait::async_trait]
+impl PaymentServiceProvider for Alipay {
+    #[tracing::instrument(skip(self, req), fields(payment_id = %id))]
+    async fn pay(
+        &self,
+        id: Uuid,
+        mut req: PayRequest,
+    ) -> Result<(PayResponse, HttpRequestJson, Option<HttpResponseJson>), PayError> {
+        if req.amount <= 0 {
+            return Err(PayError::InvalidAmount(req.amount));
+        }
+
+        let mut biz_content = json!({
+            "out_trade_no": id.simple().to_string(),
+            "total_amount": yuan(req.amount),
+            "subject": req.description,
+            "product_code": "JSAPI_PAY",
+            "op_app_id": self.app_id,
+        });
+
+        // 新接入的应用使用 buyer_open_id，旧应用仍可能使用 buyer_id
+        match (
+            req.extras.remove("buyer_open_id"),
+            req.extras.remove("buyer_id"),
+        ) {
+            (Some(open_id), _) => biz_content["buyer_open_id"] = json!(open_id),
+            (None, Some(buyer_id)) => biz_content["buyer_id"] = json!(buyer_id),
+            (None, None) => return Err(PayError::MissingExtra("buyer_open_id")),
+        }
+
+        if let Some(attach) = &req.attach {
+            // 支付宝要求 passback_params 经过 URL 编码，通知中原样返回
+            biz_content["passback_params"] =
+                json!(utf8_percent_encode(attach, NON_ALPHANUMERIC).to_string());
+        }
+
+        if !req.goods_detail.is_empty() {
+            biz_content["goods_detail"] = req
+                .goods_detail
+                .iter()
+                .map(|g| {
+                    json!({
+                        "goods_id": g.merchant_goods_id,
+                        "goods_name": g.goods_name.as_deref().unwrap_or(&g.merchant_goods_id),
+                        "quantity": g.quantity,
+                        "price": yuan(g.unit_price),
+                    })
+                })
+                .collect();
+        }
+
+        let (http_req, http_res, response) = self
+            .execute(
+                "alipay.trade.create",
+                biz_content,
+                Some(req.notify_url.as_deref().unwrap_or(&self.notify_url)),
+            )
+            .await?;
+
+        let Some(trade_no) = response["trade_no"].as_str() else {
+            tracing::error!(body = %http_res.body, "alipay trade create response missing trade_no");
+
+            return Err(PayError::Provider {
+                status: http_res.status,
+                body: http_res.body.to_string(),
+            });
+        };
+
+        Ok((
+            PayResponse {
+                // 小程序中通过 my.tradePay({ tradeNO }) 调起支付
+                provider_params: json!({ "tradeNO": trade_no }),
+                prepay_id: Some(trade_no.to_string()),
+            },
+            http_req,
+            Some(http_res),
+        ))
+    }
+
+    #[tracing::instrument(skip_all, fields(payment_id = tracing::field::Empty))]
+    async fn pay_callback(
+        &self,
+        req: http::Request<bytes::Bytes>,
+    ) -> Result<
+        (
+            PayCallbackOutcome,
+            HttpRequestJson,
+            Option<HttpResponseJson>,
+        ),
+        CallbackError,
+    > {
+        let http_req = HttpRequestJson::from_http_req(&req);
+
+        let params = self.verify_notification(&req)?;
+
+        if field(&params, "out_biz_no").is_ok() {
+            return Err(CallbackError::UnexpectedEventType("refund".to_string()));
+        }
+
+        let trade_status = field(&params, "trade_status")?;
+        if !matches!(trade_status, "TRADE_SUCCESS" | "TRADE_FINISHED") {
+            return Err(CallbackError::UnexpectedEventType(trade_status.to_string()));
+        }
+
+        let id = uuid_field(&params, "out_trade_no")?;
+
+        tracing::Span::current().record("payment_id", tracing::field::display(id));
+
+        let res = success_response();
+        let http_res = HttpResponseJson::from_http_res(&res);
+
+        Ok((
+            PayCallbackOutcome {
+                id,
+                provider_trade_no: field(&params, "trade_no")?.to_string(),
+                success_at: datetime_field(&params, "gmt_payment")?,
+                attach: field(&params, "passback_params")
+                    .ok()
+                    .map(|a| percent_decode_str(a).decode_utf8_lossy().into_owned()),
+                res,
+            },
+            http_req,
+            Some(http_res),
+        ))
+    }
+
+    #[tracing::instrument(skip(self, req), fields(payment_id = %payment_id, refund_id = %req.refund_id))]
+    async fn refund(
+        &self,
+        payment_id: Uuid,
+        req: RefundRequest,
+    ) -> Result<(RefundResponse, HttpRequestJson, Option<HttpResponseJson>), RefundError> {
+        if req.amount <= 0 {
+            return Err(RefundError::InvalidAmount(req.amount));
+        }
+
+        let biz_content = json!({
+            "out_trade_no": payment_id.simple().to_string(),
+            "out_request_no": req.refund_id.simple().to_string(),
+            "refund_amount": yuan(req.amount),
+        });
+
+        let (http_req, http_res, response) = self
+            .execute("alipay.trade.refund", biz_content, None)
+            .await?;
+
+        let Some(trade_no) = response["trade_no"].as_str() else {
+            tracing::error!(body = %http_res.body, "alipay refund response missing trade_no");
+
+            return Err(RefundError::Provider {
+                status: http_res.status,
+                body: http_res.body.to_string(),
+            });
+        };
+
+        // 退款接口同步返回结果，fund_change 为 Y 表示资金已退回
+        let status = if response["fund_change"] == "Y" {
+            "SUCCESS"
+        } else {
+            "PROCESSING"
+        };
+
+        Ok((
+            RefundResponse {
+                refund_id: req.refund_id,
+                // 支付宝的退款没有单独的编号，使用原交易号
+                provider_refund_no: trade_no.to_string(),
+                status: status.to_string(),
+            },
+            http_req,
+            Some(http_res),
+        ))
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        fields(payment_id = tracing::field::Empty, refund_id = tracing::field::Empty)
+    )]
+    async fn refund_callback(
+        &self,
+        req: http::Request<bytes::Bytes>,
+    ) -> Result<
+        (
+            RefundCallbackOutcome,
+            HttpRequestJson,
+            Option<HttpResponseJson>,
+        ),
+        CallbackError,
+    > {
+        let http_req = HttpRequestJson::from_http_req(&req);
+
+        let params = self.verify_notification(&req)?;
+
+        if field(&params, "out_biz_no").is_err() {
+            let trade_status = params.get("trade_status").cloned().unwrap_or_default();
+
+            return Err(CallbackError::UnexpectedEventType(trade_status));
+        }
+
+        let refund_id = uuid_field(&params, "out_biz_no")?;
+
+        tracing::Span::current()
+            .record(
+                "payment_id",
+                tracing::field::display(field(&params, "out_trade_no")?),
+            )
+            .record("refund_id", tracing::field::display(refund_id));
+
+        // 交易状态同步通知中没有单独的退款状态：交易仍有效、带有退款时间且累计退款金额为正时视为退款成功，
+        // 否则按处理中记录，等待后续通知或主动查询
+        let trade_status = field(&params, "trade_status")?;
+        let refund_fee = field(&params, "refund_fee")
+            .ok()
+            .map(|fee| parse_yuan(fee).ok_or(CallbackError::InvalidField("refund_fee")))
+            .transpose()?;
+        let refunded = matches!(
+            trade_status,
+            "TRADE_SUCCESS" | "TRADE_FINISHED" | "TRADE_CLOSED"
+        ) && refund_fee.is_some_and(|fee| fee > 0)
+            && field(&params, "gmt_refund").is_ok();
+
+        let (status, success_at) = if refunded {
+            (
+                RefundStatus::Success,
+                Some(datetime_field(&params, "gmt_refund")?),
+            )
+        } else {
+            tracing::warn!(
+                trade_status,
+                ?refund_fee,
+                "alipay refund notification without a completed refund"
+            );
+
+            (RefundStatus::Pending, None)
+        };
+
+        let res = success_response();
+        let http_res = HttpResponseJson::from_http_res(&res);
+
+        Ok((
+            RefundCallbackOutcome {
+                refund_id,
+                provider_refund_no: field(&params, "trade_no")?.to_string(),
+                success_at,
+                status,
+                res,
+            },
+            http_req,
+            Some(http_res),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::signature::{SignatureEncoding as _, Signer as _, Verifier as _};
+
+    use super::*;
+
+    fn provider() -> (Alipay, rsa::RsaPrivateKey) {
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+
+        let provider = Alipay::new(
+            "2021000000000000".into(),
+            "https://example.com/alipay".into(),
+            key.clone(),
+            key.to_public_key(),
+        );
+
+        (provider, key)
+    }
+
+    /// 按支付宝通知的规则签名并编码为表单
+    fn notification(
+        key: rsa::RsaPrivateKey,
+        params: &[(&str, &str)],
+    ) -> http::Request<bytes::Bytes> {
+        let mut params = params.iter().copied().collect::<BTreeMap<_, _>>();
+        params.insert("sign_type", "RSA2");
+
+        let content = params
+            .iter()
+            .filter(|(k, _)| **k != "sign_type")
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        use base64::prelude::*;
+        let sign = BASE64_STANDARD.encode(
+            SigningKey::<Sha256>::new(key)
+                .sign(content.as_bytes())
+                .to_bytes(),
+        );
+
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .append_pair("sign", &sign)
+            .finish();
+
+        http::Request::builder()
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(bytes::Bytes::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pay_callback_parses_notification() {
+        let (provider, key) = provider();
+        let id = Uuid::now_v7();
+
+        let req = notification(
+            key,
+            &[
+                ("app_id", "2021000000000000"),
+                ("notify_type", "trade_status_sync"),
+                ("out_trade_no", &id.simple().to_string()),
+                ("trade_no", "2024010122001400000000000001"),
+                ("trade_status", "TRADE_SUCCESS"),
+                ("gmt_payment", "2024-01-01 12:00:00"),
+                ("passback_params", "order%3D1"),
+            ],
+        );
+        assert!(!Alipay::is_refund_notification(req.body()));
+
+        let (outcome, _, _) = provider.pay_callback(req).await.unwrap();
+
+        assert_eq!(outcome.id, id);
+        assert_eq!(outcome.provider_trade_no, "2024010122001400000000000001");
+        assert_eq!(outcome.success_at.unix_timestamp(), 1704081600);
+        assert_eq!(outcome.attach.as_deref(), Some("order=1"));
+        assert_eq!(outcome.res.body(), "success");
+    }
+
+    #[tokio::test]
+    async fn callbacks_reject_mismatched_notifications() {
+        let (provider, key) = provider();
+        let refund_id = Uuid::now_v7().simple().to_string();
+
+        let refund = notification(
+            key.clone(),
+            &[
+                ("app_id", "2021000000000000"),
+                ("out_trade_no", "1"),
+                ("out_biz_no", &refund_id),
+                ("trade_no", "2024010122001400000000000001"),
+                ("trade_status", "TRADE_SUCCESS"),
+                ("gmt_refund", "2024-01-01 12:00:00.000"),
+            ],
+        );
+        assert!(Alipay::is_refund_notification(refund.body()));
+        assert!(matches!(
+            provider.pay_callback(refund).await.err().unwrap(),
+            CallbackError::UnexpectedEventType(_)
+        ));
+
+        let other_app = notification(
+            key.clone(),
+            &[("app_id", "2021000000000001"), ("out_biz_no", &refund_id)],
+        );
+        assert!(matches!(
+            provider.refund_callback(other_app).await.err().unwrap(),
+            CallbackError::AppIdMismatch(_)
+        ));
+
+        let mut tampered = notification(key, &[("app_id", "2021000000000000")]);
+        *tampered.body_mut() = bytes::Bytes::from(format!(
+            "{}&trade_status=TRADE_SUCCESS",
+            String::from_utf8_lossy(tampered.body())
+        ));
+        assert!(matches!(
+            provider.pay_callback(tampered).await.err().unwrap(),
+            CallbackError::InvalidSignature
+        ));
+    }
+
+    #[test]
+    fn formats_amount_in_yuan() {
+        assert_eq!(yuan(1), "0.01");
+        assert_eq!(yuan(100), "1.00");
+        assert_eq!(yuan(12345), "123.45");
+        assert_eq!(yuan(-5), "-0.05");
+        assert_eq!(yuan(-105), "-1.05");
+
+        assert_eq!(parse_yuan("1.00"), Some(100));
+        assert_eq!(parse_yuan("0.5"), Some(50));
+        assert_eq!(parse_yuan("12"), Some(1200));
+        assert_eq!(parse_yuan("-1.00"), None);
+        assert_eq!(parse_yuan("1.005"), None);
+        assert_eq!(parse_yuan(""), None);
+    }
+
+    #[tokio::test]
+    async fn refund_callback_derives_status_from_notification() {
+        let (provider, key) = provider();
+        let refund_id = Uuid::now_v7();
+
+        let refund = |trade_status, refund_fee| {
+            notification(
+                key.clone(),
+                &[
+                    ("app_id", "2021000000000000"),
+                    ("out_trade_no", "1"),
+                    ("out_biz_no", &refund_id.simple().to_string()),
+                    ("trade_no", "2024010122001400000000000001"),
+                    ("trade_status", trade_status),
+                    ("refund_fee", refund_fee),
+                    ("gmt_refund", "2024-01-01 12:00:00.000"),
+                ],
+            )
+        };
+
+        let (outcome, _, _) = provider
+            .refund_callback(refund("TRADE_CLOSED", "1.00"))
+            .await
+            .unwrap();
+        assert_eq!(outcome.refund_id, refund_id);
+        assert_eq!(outcome.status, RefundStatus::Success);
+        assert_eq!(outcome.success_at.unwrap().unix_timestamp(), 1704081600);
+
+        let (outcome, _, _) = provider
+            .refund_callback(refund("TRADE_SUCCESS", "0.00"))
+            .await
+            .unwrap();
+        assert_eq!(outcome.status, RefundStatus::Pending);
+        assert!(outcome.success_at.is_none());
+
+        let (outcome, _, _) = provider
+            .refund_callback(refund("WAIT_BUYER_PAY", "1.00"))
+            .await
+            .unwrap();
+        assert_eq!(outcome.status, RefundStatus::Pending);
+
+        assert!(matches!(
+            provider
+                .refund_callback(refund("TRADE_SUCCESS", "abc"))
+                .await
+                .err()
+                .unwrap(),
+            CallbackError::InvalidField("refund_fee")
+        ));
+    }
+
+    /// 按支付宝同步响应的规则对 `{method}_response` 的原始文本签名
+    fn gateway_response(key: &rsa::RsaPrivateKey, response_key: &str, response: &str) -> String {
+        use base64::prelude::*;
+        let sign = BASE64_STANDARD.encode(
+            SigningKey::<Sha256>::new(key.clone())
+                .sign(response.as_bytes())
+                .to_bytes(),
+        );
+
+        format!(r#"{{"{response_key}":{response},"sign":"{sign}"}}"#)
+    }
+
+    /// 只处理一次请求的网关，返回网关地址与收到的请求
+    async fn mock_gateway(body: String) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0; 64 * 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..n]).into_owned();
+
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(res.as_bytes()).await.unwrap();
+
+            req
+        });
+
+        (format!("http://{addr}/gateway.do"), server)
+    }
+
+    #[tokio::test]
+    async fn refund_rejects_tampered_response() {
+        let (provider, key) = provider();
+
+        let signed = gateway_response(
+            &key,
+            "alipay_trade_refund_response",
+            r#"{"code":"40004","msg":"Business Failed","sub_code":"ACQ.TRADE_NOT_EXIST","sub_msg":"交易不存在"}"#,
+        );
+        // 保留原签名，把业务结果改为成功
+        let tampered = signed
+            .replace(r#""code":"40004""#, r#""code":"10000""#)
+            .replace(
+                r#""sub_code":"ACQ.TRADE_NOT_EXIST""#,
+                r#""trade_no":"2024010122001400000000000001","fund_change":"Y""#,
+            );
+
+        let (url, _server) = mock_gateway(tampered).await;
+        let provider = provider.gateway_url(url);
+
+        let err = provider
+            .refund(
+                Uuid::now_v7(),
+                RefundRequest {
+                    refund_id: Uuid::now_v7(),
+                    provider_trade_no: "2024010122001400000000000001".into(),
+                    amount: 100,
+                    total: 100,
+                    notify_url: None,
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, RefundError::InvalidResponseSignature));
+    }
+
+    #[tokio::test]
+    async fn pay_against_mock_server() {
+        let (provider, key) = provider();
+
+        let (url, server) = mock_gateway(gateway_response(
+            &key,
+            "alipay_trade_create_response",
+            r#"{"code":"10000","msg":"Success","out_trade_no":"1","trade_no":"2024010122001400000000000001"}"#,
+        ))
+        .await;
+        let provider = provider.gateway_url(url);
+
+        let (res, _, _) = provider
+            .pay(
+                Uuid::now_v7(),
+                PayRequest {
+                    biz_id: Uuid::now_v7(),
+                    amount: 100,
+                    description: "test".into(),
+                    extras: [("buyer_open_id".to_string(), "open_id".to_string())].into(),
+                    idempotency_key: None,
+                    attach: Some("order=1".into()),
+                    goods_detail: vec![],
+                    notify_url: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            res.prepay_id.as_deref(),
+            Some("2024010122001400000000000001")
+        );
+        assert_eq!(
+            res.provider_params["tradeNO"],
+            "2024010122001400000000000001"
+        );
+
+        let req = server.await.unwrap();
+        assert!(req.starts_with("POST /gateway.do "));
+
+        let form = req.split("\r\n\r\n").nth(1).unwrap();
+        let params = form_urlencoded::parse(form.as_bytes())
+            .into_owned()
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(params["method"], "alipay.trade.create");
+
+        let biz_content =
+            serde_json::from_str::<serde_json::Value>(&params["biz_content"]).unwrap();
+        assert_eq!(biz_content["total_amount"], "1.00");
+        assert_eq!(biz_content["buyer_open_id"], "open_id");
+        assert_eq!(biz_content["passback_params"], "order%3D1");
+
+        // 请求签名中 sign_type 参与签名
+        let content = params
+            .iter()
+            .filter(|(k, _)| *k != "sign")
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        use base64::prelude::*;
+        let sign = BASE64_STANDARD.decode(&params["sign"]).unwrap();
+        VerifyingKey::<Sha256>::new(key.to_public_key())
+            .verify(content.as_bytes(), &sign.as_slice().try_into().unwrap())
+            .unwrap();
+    }
+}
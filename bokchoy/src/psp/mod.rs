@@ -1,29 +1,213 @@
 use std::collections::HashMap;
 
+use serde::Serialize;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
 use crate::event::{HttpRequestJson, HttpResponseJson};
 
+mod alipay;
 mod wxpay_jsapi;
 
-pub use wxpay_jsapi::WxPayJsapi;
+pub use alipay::{Alipay, AlipayApiError};
+pub use wxpay_jsapi::{DecryptError, WxPayApiError, WxPayJsapi};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PayError {
+    #[error("provider {0:?} is not registered")]
+    UnknownProvider(crate::Provider),
+
+    /// [`PayRequest::extras`] 缺少渠道所需的字段
+    #[error("missing required extra {0:?}")]
+    MissingExtra(&'static str),
+
+    /// 金额必须为正数
+    #[error("amount must be positive, got {0}")]
+    InvalidAmount(i64),
+
+    /// 支付渠道返回了非 2xx 响应
+    #[error("provider responded with status {status}: {body}")]
+    Provider { status: u16, body: String },
+
+    /// 该支付单已在渠道支付成功，或幂等键对应的支付单已支付，调用方应查询支付状态而不是视为失败
+    #[error("order has already been paid")]
+    OrderPaid,
+
+    /// 该支付单已在渠道关闭
+    #[error("order has been closed")]
+    OrderClosed,
+
+    /// 商户订单号已被使用，且下单参数与之前不一致
+    #[error("out_trade_no has already been used")]
+    OutTradeNoUsed,
+
+    /// 渠道同步响应的签名校验失败，响应内容不可信
+    #[error("provider response signature is invalid")]
+    InvalidResponseSignature,
+
+    #[error(transparent)]
+    WxPay(#[from] WxPayApiError),
+
+    #[error(transparent)]
+    Alipay(#[from] AlipayApiError),
+
+    #[error("failed to reach provider: {0}")]
+    Http(#[from] reqwest::Error),
+
+    /// 相同幂等键的支付单仍在创建中
+    #[error("payment with idempotency key {0} is still being created")]
+    IdempotencyKeyInFlight(String),
+
+    /// 幂等键已用于金额、业务 id 或渠道不同的待支付支付单
+    #[error("idempotency key {0} was used for a different payment")]
+    IdempotencyKeyMismatch(String),
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RefundError {
+    #[error("provider {0:?} is not registered")]
+    UnknownProvider(crate::Provider),
+
+    /// 支付单尚未支付成功，没有渠道交易号
+    #[error("payment {0} has no provider trade number")]
+    MissingTradeNo(Uuid),
+
+    /// 支付金额已全部退款或正在退款中
+    #[error("payment {0} has nothing left to refund")]
+    NothingToRefund(Uuid),
+
+    /// 金额必须为正数
+    #[error("amount must be positive, got {0}")]
+    InvalidAmount(i64),
+
+    /// 支付渠道返回了无法识别的响应
+    #[error("provider responded with status {status}: {body}")]
+    Provider { status: u16, body: String },
+
+    /// 渠道同步响应的签名校验失败，响应内容不可信
+    #[error("provider response signature is invalid")]
+    InvalidResponseSignature,
+
+    #[error(transparent)]
+    WxPay(#[from] WxPayApiError),
+
+    #[error(transparent)]
+    Alipay(#[from] AlipayApiError),
+
+    #[error("failed to reach provider: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CallbackError {
+    #[error("provider {0:?} is not registered")]
+    UnknownProvider(crate::Provider),
+
+    #[error("missing or malformed header {0}")]
+    MissingHeader(&'static str),
+
+    /// 表单格式的通知（如支付宝）缺少字段或字段格式错误
+    #[error("missing or malformed field {0}")]
+    InvalidField(&'static str),
+
+    /// 通知中的 app_id 与当前配置的应用不一致
+    #[error("notification is for another app {0}")]
+    AppIdMismatch(String),
+
+    /// 回调 body 超出构建时设置的 `max_callback_body_size`
+    #[error("callback body of {size} bytes exceeds the limit of {limit} bytes")]
+    PayloadTooLarge { size: usize, limit: usize },
+
+    #[error("unknown platform certificate serial {0}")]
+    UnknownSerial(String),
+
+    /// 回调时间戳超出允许窗口，可能是重放
+    #[error("callback timestamp {0} is outside the allowed window")]
+    StaleTimestamp(String),
+
+    #[error("callback signature is invalid")]
+    InvalidSignature,
+
+    /// 事件类型与接收回调的接口不符，如支付通知被发送到退款回调地址
+    #[error("unexpected callback event type {0}")]
+    UnexpectedEventType(String),
+
+    /// 通知 body 或解密后的资源不是预期的 JSON 格式
+    #[error("malformed callback body: {0}")]
+    BadBody(serde_json::Error),
+
+    /// 通知的 resource_type 不是 `encrypt-resource`
+    #[error("unexpected callback resource type {0}")]
+    UnexpectedResourceType(String),
+
+    /// 通知中的 appid、商户号或子商户号与当前配置不一致
+    #[error("notification is for another merchant {0}")]
+    MerchantMismatch(String),
+
+    /// 支付通知的交易状态不是 `SUCCESS`
+    #[error("unexpected trade state {0}")]
+    UnexpectedTradeState(String),
+
+    #[error(transparent)]
+    Decrypt(#[from] DecryptError),
+
+    /// 构建时未设置连接池，不能使用 `*_pooled` 方法
+    #[error("database pool is not set")]
+    MissingPool,
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
 
 pub struct PayRequest {
     pub biz_id: Uuid,
     pub amount: i64,
     pub description: String,
+    /// 渠道所需的额外参数，如 [`WxPayJsapi`] 需要 `openid`
     pub extras: HashMap<String, String>,
+    /// 幂等键，存在相同幂等键的待支付支付单时直接返回其支付参数，不再重复下单；
+    /// 该支付单的金额、`biz_id` 或渠道与本次请求不一致时返回 [`PayError::IdempotencyKeyMismatch`]，
+    /// 已支付时返回 [`PayError::OrderPaid`]
+    pub idempotency_key: Option<String>,
+    /// 商户附加数据，渠道在支付回调中原样返回，微信支付限制为 128 字节
+    pub attach: Option<String>,
+    /// 商品明细，为空时不传
+    pub goods_detail: Vec<GoodsDetail>,
+    /// 覆盖渠道配置的支付回调地址，如多租户时按租户区分回调路径
+    pub notify_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GoodsDetail {
+    pub merchant_goods_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wechatpay_goods_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub goods_name: Option<String>,
+    pub quantity: i64,
+    /// 单价，单位为分
+    pub unit_price: i64,
 }
 
 pub struct PayResponse {
+    /// 客户端调起支付所需的参数，格式由渠道决定
     pub provider_params: serde_json::Value,
+    /// 渠道返回的预支付交易会话标识，供 H5、App 等需自行调起支付的场景使用
+    pub prepay_id: Option<String>,
 }
 
 pub struct PayCallbackOutcome {
     pub id: Uuid,
     pub provider_trade_no: String,
     pub success_at: OffsetDateTime,
+    /// 下单时传入的 [`PayRequest::attach`]
+    pub attach: Option<String>,
     pub res: http::Response<String>,
 }
 
@@ -32,6 +216,8 @@ pub struct RefundRequest {
     pub provider_trade_no: String,
     pub amount: i64,
     pub total: i64,
+    /// 覆盖渠道配置的退款回调地址；支付宝的退款通知发送到下单时的地址，忽略该字段
+    pub notify_url: Option<String>,
 }
 
 pub struct RefundResponse {
@@ -48,35 +234,43 @@ pub struct RefundCallbackOutcome {
     pub res: http::Response<String>,
 }
 
+/// 支付渠道，通过 [`PaymentService::builder`](crate::PaymentService::builder) 注册；
+/// 方法签名中的请求/响应记录类型不对外导出，因此只能使用本 crate 提供的实现
 #[async_trait::async_trait]
-pub(crate) trait PaymentServiceProvider: Send + Sync {
+pub trait PaymentServiceProvider: Send + Sync {
     async fn pay(
         &self,
         id: Uuid,
         req: PayRequest,
-    ) -> (PayResponse, HttpRequestJson, Option<HttpResponseJson>);
+    ) -> Result<(PayResponse, HttpRequestJson, Option<HttpResponseJson>), PayError>;
 
     async fn pay_callback(
         &self,
         req: http::Request<bytes::Bytes>,
-    ) -> (
-        PayCallbackOutcome,
-        HttpRequestJson,
-        Option<HttpResponseJson>,
-    );
+    ) -> Result<
+        (
+            PayCallbackOutcome,
+            HttpRequestJson,
+            Option<HttpResponseJson>,
+        ),
+        CallbackError,
+    >;
 
     async fn refund(
         &self,
         id: Uuid,
         req: RefundRequest,
-    ) -> (RefundResponse, HttpRequestJson, Option<HttpResponseJson>);
+    ) -> Result<(RefundResponse, HttpRequestJson, Option<HttpResponseJson>), RefundError>;
 
     async fn refund_callback(
         &self,
         req: http::Request<bytes::Bytes>,
-    ) -> (
-        RefundCallbackOutcome,
-        HttpRequestJson,
-        Option<HttpResponseJson>,
-    );
+    ) -> Result<
+        (
+            RefundCallbackOutcome,
+            HttpRequestJson,
+            Option<HttpResponseJson>,
+        ),
+        CallbackError,
+    >;
 }
@@ -2,22 +2,44 @@ use aes_gcm::{
     Aes256Gcm, Key, KeyInit as _, Nonce,
     aead::{Aead, Payload},
 };
-use http::HeaderValue;
+use rsa::pkcs1v15::{SigningKey, VerifyingKey};
 use serde::Deserialize;
 use serde_json::json;
-use time::OffsetDateTime;
+use sha2::Sha256;
+use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
 use crate::{
     RefundStatus,
     event::{HttpRequestJson, HttpResponseJson},
     psp::{
-        PayCallbackOutcome, PayRequest, PayResponse, PaymentServiceProvider, RefundCallbackOutcome,
-        RefundRequest, RefundResponse,
+        CallbackError, PayCallbackOutcome, PayError, PayRequest, PayResponse,
+        PaymentServiceProvider, RefundCallbackOutcome, RefundError, RefundRequest, RefundResponse,
     },
-    utils::{get_body_auth_header, pay_sign, verify_response},
+    utils::{get_body_auth_header, pay_sign, verify_response, verify_timestamp},
 };
 
+/// 微信支付 API 的错误响应
+/// https://pay.weixin.qq.com/doc/v3/merchant/4012081568
+#[derive(Debug, Clone, Deserialize, thiserror::Error)]
+#[error("wxpay api error {status} {code}: {message}")]
+pub struct WxPayApiError {
+    pub code: String,
+    pub message: String,
+    #[serde(skip)]
+    pub status: u16,
+}
+
+impl WxPayApiError {
+    /// 响应体不是 `{code, message}` 格式时返回 `None`
+    pub fn parse(status: u16, body: &str) -> Option<Self> {
+        let mut err = serde_json::from_str::<Self>(body).ok()?;
+        err.status = status;
+
+        Some(err)
+    }
+}
+
 #[derive(Debug)]
 pub struct WxPayJsapi {
     appid: String,
@@ -25,14 +47,21 @@ pub struct WxPayJsapi {
     payment_notify_url: String,
     refund_notify_url: String,
     merchant_cert_serial_no: String,
-    merchant_cert_private_key: rsa::RsaPrivateKey,
+    /// 由商户私钥预先构建，避免每次请求复制私钥
+    signing_key: SigningKey<Sha256>,
     wxpay_public_key_id: String,
-    wxpay_public_key: rsa::RsaPublicKey,
+    verifying_key: VerifyingKey<Sha256>,
     apiv3_key: String,
+    secondary_apiv3_key: Option<String>,
+    sub_mchid: Option<String>,
+    sub_appid: Option<String>,
+    timestamp_window: Duration,
+    api_base_url: String,
     reqwest: reqwest::Client,
 }
 
 impl WxPayJsapi {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         (appid, mchid): (String, String),
         payment_notify_url: String,
@@ -51,217 +80,320 @@ impl WxPayJsapi {
             payment_notify_url,
             refund_notify_url,
             merchant_cert_serial_no,
-            merchant_cert_private_key,
+            signing_key: SigningKey::new(merchant_cert_private_key),
             wxpay_public_key_id,
-            wxpay_public_key,
+            verifying_key: VerifyingKey::new(wxpay_public_key),
             apiv3_key,
+            secondary_apiv3_key: None,
+            sub_mchid: None,
+            sub_appid: None,
+            timestamp_window: Duration::minutes(5),
+            api_base_url: "https://api.mch.weixin.qq.com".to_string(),
             reqwest,
         }
     }
+
+    /// 以服务商模式下单，`appid`/`mchid` 视为服务商的 `sp_appid`/`sp_mchid`
+    ///
+    /// 传入 `sub_appid` 时，`openid` 为子商户 appid 下的 `sub_openid`，否则为 `sp_openid`
+    pub fn sub_merchant(mut self, sub_mchid: String, sub_appid: Option<String>) -> Self {
+        self.sub_mchid = Some(sub_mchid);
+        self.sub_appid = sub_appid;
+
+        self
+    }
+
+    /// 回调时间戳与当前时间允许的最大偏差，超出则视为重放，默认 5 分钟
+    pub fn timestamp_window(mut self, window: Duration) -> Self {
+        self.timestamp_window = window;
+
+        self
+    }
+
+    /// 微信支付 API 的地址，默认为 `https://api.mch.weixin.qq.com`，测试时可指向 mock 服务
+    pub fn api_base_url(mut self, url: String) -> Self {
+        self.api_base_url = url;
+
+        self
+    }
+
+    /// 轮换 APIv3 密钥期间的旧密钥，回调用主密钥解密失败时再尝试该密钥
+    pub fn secondary_apiv3_key(mut self, key: String) -> Self {
+        self.secondary_apiv3_key = Some(key);
+
+        self
+    }
+
+    fn apiv3_keys(&self) -> Vec<&[u8]> {
+        std::iter::once(&self.apiv3_key)
+            .chain(&self.secondary_apiv3_key)
+            .map(String::as_bytes)
+            .collect()
+    }
+
+    /// 校验回调通知的签名头、时间戳与签名
+    fn verify_notification(&self, req: &http::Request<bytes::Bytes>) -> Result<(), CallbackError> {
+        let header = |name: &'static str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .ok_or(CallbackError::MissingHeader(name))
+        };
+
+        let timestamp = header("Wechatpay-Timestamp")?;
+        let nonce = header("Wechatpay-Nonce")?;
+        let cert_serial = header("Wechatpay-Serial")?;
+        let sign = header("Wechatpay-Signature")?;
+
+        if cert_serial != self.wxpay_public_key_id {
+            // TODO: 平台证书
+            return Err(CallbackError::UnknownSerial(cert_serial.to_string()));
+        }
+
+        verify_timestamp(timestamp, OffsetDateTime::now_utc(), self.timestamp_window)
+            .map_err(|_| CallbackError::StaleTimestamp(timestamp.to_string()))?;
+
+        verify_response(&self.verifying_key, sign, timestamp, nonce, req.body())
+            .map_err(|_| CallbackError::InvalidSignature)
+    }
 }
 
 #[async_trait::async_trait]
 impl PaymentServiceProvider for WxPayJsapi {
+    #[tracing::instrument(skip(self, req), fields(payment_id = %id))]
     async fn pay(
         &self,
         id: Uuid,
         mut req: PayRequest,
-    ) -> (PayResponse, HttpRequestJson, Option<HttpResponseJson>) {
+    ) -> Result<(PayResponse, HttpRequestJson, Option<HttpResponseJson>), PayError> {
         const API_PATH: &str = "/v3/pay/transactions/jsapi";
+        const PARTNER_API_PATH: &str = "/v3/pay/partner/transactions/jsapi";
+
+        let payer_openid = req
+            .extras
+            .remove("openid")
+            .ok_or(PayError::MissingExtra("openid"))?;
+
+        let notify_url = req
+            .notify_url
+            .as_deref()
+            .unwrap_or(&self.payment_notify_url);
+
+        let (api_path, mut body) = match &self.sub_mchid {
+            None => (
+                API_PATH,
+                json!({
+                    "appid": self.appid,
+                    "mchid": self.mchid,
+                    "description": req.description,
+                    "out_trade_no": id.simple().to_string(),
+                    "notify_url": notify_url,
+                    "amount": { "total": req.amount, "currency": "CNY" },
+                    "payer": { "openid": payer_openid }
+                }),
+            ),
+            Some(sub_mchid) => {
+                let mut body = json!({
+                    "sp_appid": self.appid,
+                    "sp_mchid": self.mchid,
+                    "sub_mchid": sub_mchid,
+                    "description": req.description,
+                    "out_trade_no": id.simple().to_string(),
+                    "notify_url": notify_url,
+                    "amount": { "total": req.amount, "currency": "CNY" },
+                    "payer": { "sp_openid": payer_openid }
+                });
+
+                if let Some(sub_appid) = &self.sub_appid {
+                    body["sub_appid"] = json!(sub_appid);
+                    body["payer"] = json!({ "sub_openid": payer_openid });
+                }
+
+                (PARTNER_API_PATH, body)
+            }
+        };
 
-        let payer_openid = req.extras.remove("openid").unwrap();
+        if let Some(attach) = &req.attach {
+            body["attach"] = json!(attach);
+        }
 
-        let body = json!({
-            "appid": self.appid,
-            "mchid": self.mchid,
-            "description": req.description,
-            "out_trade_no": id.simple().to_string(),
-            "notify_url": self.payment_notify_url,
-            "amount": { "total": req.amount, "currency": "CNY" },
-            "payer": { "openid": payer_openid }
-        });
+        if !req.goods_detail.is_empty() {
+            body["detail"] = json!({ "goods_detail": req.goods_detail });
+        }
 
         let body_str = serde_json::to_string(&body).unwrap();
 
         let auth_header = get_body_auth_header(
             &self.mchid,
-            self.merchant_cert_private_key.clone(),
+            &self.signing_key,
             &self.merchant_cert_serial_no,
             http::Method::POST,
-            API_PATH,
+            api_path,
             &body_str,
         );
 
         let req = self
             .reqwest
-            .post(format!("https://api.mch.weixin.qq.com{API_PATH}"))
+            .post(format!("{}{api_path}", self.api_base_url))
             .body(body_str)
             .header("Authorization", auth_header)
             .header("User-Agent", "bokchoy")
             .header("Accept", "application/json")
             .header("Content-Type", "application/json")
-            .build()
-            .unwrap();
+            .build()?;
 
         let http_req = HttpRequestJson::from_reqwest_req(&req, body);
 
-        let res = self.reqwest.execute(req).await.unwrap();
+        let res = self
+            .reqwest
+            .execute(req)
+            .await
+            .inspect_err(|e| tracing::error!(error = %e, "wxpay prepay request failed"))?;
 
-        if res.status().is_success() {
-            #[derive(Deserialize)]
-            struct PrePayResponse {
-                prepay_id: String,
-            }
+        if !res.status().is_success() {
+            let status = res.status().as_u16();
+            let body = res.text().await?;
 
-            let (http_res, body) = {
-                let status = res.status().as_u16();
-
-                let headers = res
-                    .headers()
-                    .iter()
-                    .filter_map(|(k, v)| v.to_str().map(|v| (k.to_string(), v.to_string())).ok())
-                    .collect::<Vec<_>>();
-
-                let body = res.json::<serde_json::Value>().await.unwrap();
-
-                (
-                    HttpResponseJson {
-                        status,
-                        headers,
-                        body: body.clone(),
-                    },
-                    body,
-                )
-            };
-
-            let body = serde_json::from_value::<PrePayResponse>(body).unwrap();
-
-            let (timestamp, nonce, sign) = pay_sign(
-                &self.appid,
-                self.merchant_cert_private_key.clone(),
-                &body.prepay_id,
-            );
+            tracing::error!(status, body, "wxpay prepay request rejected");
 
-            let params = json!({
-                "timeStamp": timestamp.to_string(),
-                "nonceStr": nonce,
-                "package": format!("prepay_id={}", body.prepay_id),
-                "signType": "RSA",
-                "paySign": sign,
-            });
+            return Err(prepay_error(status, body));
+        }
 
-            return (
-                PayResponse {
-                    provider_params: params,
-                },
-                http_req,
-                Some(http_res),
-            );
-        } else {
-            println!("{:?}", res.text().await);
-            todo!();
+        #[derive(Deserialize)]
+        struct PrePayResponse {
+            prepay_id: String,
         }
+
+        let (http_res, body) = {
+            let status = res.status().as_u16();
+
+            let headers = res
+                .headers()
+                .iter()
+                .filter_map(|(k, v)| v.to_str().map(|v| (k.to_string(), v.to_string())).ok())
+                .collect::<Vec<_>>();
+
+            let body = res.json::<serde_json::Value>().await?;
+
+            (
+                HttpResponseJson {
+                    status,
+                    headers,
+                    body: body.clone(),
+                },
+                body,
+            )
+        };
+
+        let Ok(body) = serde_json::from_value::<PrePayResponse>(body) else {
+            tracing::error!(body = %http_res.body, "wxpay prepay response missing prepay_id");
+
+            return Err(PayError::Provider {
+                status: http_res.status,
+                body: http_res.body.to_string(),
+            });
+        };
+
+        // 使用 sub_openid 下单时，需以子商户 appid 调起支付
+        let (timestamp, nonce, sign) = pay_sign(
+            self.sub_appid.as_deref().unwrap_or(&self.appid),
+            &self.signing_key,
+            &body.prepay_id,
+        );
+
+        let params = json!({
+            "timeStamp": timestamp.to_string(),
+            "nonceStr": nonce,
+            "package": format!("prepay_id={}", body.prepay_id),
+            "signType": "RSA",
+            "paySign": sign,
+        });
+
+        Ok((
+            PayResponse {
+                provider_params: params,
+                prepay_id: Some(body.prepay_id),
+            },
+            http_req,
+            Some(http_res),
+        ))
     }
 
+    #[tracing::instrument(skip_all, fields(payment_id = tracing::field::Empty))]
     async fn pay_callback(
         &self,
         req: http::Request<bytes::Bytes>,
-    ) -> (
-        PayCallbackOutcome,
-        HttpRequestJson,
-        Option<HttpResponseJson>,
-    ) {
+    ) -> Result<
+        (
+            PayCallbackOutcome,
+            HttpRequestJson,
+            Option<HttpResponseJson>,
+        ),
+        CallbackError,
+    > {
         let http_req = HttpRequestJson::from_http_req(&req);
 
-        let header = req.headers();
+        self.verify_notification(&req)?;
 
-        let Some(Ok(timestamp)) = header.get("Wechatpay-Timestamp").map(HeaderValue::to_str) else {
-            panic!();
-        };
-        let Some(Ok(nonce)) = header.get("Wechatpay-Nonce").map(HeaderValue::to_str) else {
-            panic!();
-        };
-        let Some(Ok(cert_serial)) = header.get("Wechatpay-Serial").map(HeaderValue::to_str) else {
-            panic!();
-        };
-        let Some(Ok(sign)) = header.get("Wechatpay-Signature").map(HeaderValue::to_str) else {
-            panic!();
-        };
+        let body = serde_json::from_slice::<serde_json::Value>(req.body())
+            .map_err(CallbackError::BadBody)?;
 
-        if cert_serial != self.wxpay_public_key_id {
-            // TODO: 平台证书
-            panic!();
-        }
-
-        if verify_response(
-            self.wxpay_public_key.clone(),
-            sign,
-            timestamp,
-            nonce,
-            req.body(),
-        )
-        .is_err()
-        {
-            panic!();
-        }
-
-        let Ok(body) = serde_json::from_slice::<serde_json::Value>(req.body()) else {
-            panic!();
-        };
+        check_event_type(&body, PAY_EVENT_TYPES)?;
 
-        if body["event_type"] != "TRANSACTION.SUCCESS" {
-            panic!();
-        }
+        check_resource_type(&body)?;
 
-        if body["resource_type"] != "encrypt-resource" {
-            panic!();
-        }
+        let encrypted = serde_json::from_value::<EncryptedResource>(body["resource"].clone())
+            .map_err(CallbackError::BadBody)?;
 
-        let encrypted =
-            serde_json::from_value::<EncryptedResource>(body["resource"].clone()).unwrap();
+        let plain_text = encrypted.decrypt_with_keys(&self.apiv3_keys(), "transaction")?;
 
-        let plain_text = encrypted.decrypt(self.apiv3_key.as_bytes()).unwrap();
+        let resource =
+            serde_json::from_str::<PlainResource>(&plain_text).map_err(CallbackError::BadBody)?;
 
-        let resource = serde_json::from_str::<PlainResource>(&plain_text).unwrap();
+        tracing::Span::current()
+            .record("payment_id", tracing::field::display(resource.out_trade_no));
 
-        if resource.appid != self.appid || resource.mchid != self.mchid {
-            panic!();
+        if resource.appid != self.appid
+            || resource.mchid != self.mchid
+            || resource.sub_mchid != self.sub_mchid
+        {
+            return Err(CallbackError::MerchantMismatch(resource.mchid));
         }
 
         if resource.trade_state != "SUCCESS" {
-            panic!();
+            return Err(CallbackError::UnexpectedTradeState(resource.trade_state));
         }
 
-        let res = http::Response::builder()
-            .header("Content-Type", "application/json")
-            .body(json!({}).to_string())
-            .unwrap();
+        let res = ack_response();
 
         let http_res = HttpResponseJson::from_http_res(&res);
 
-        (
+        Ok((
             PayCallbackOutcome {
                 id: resource.out_trade_no,
                 provider_trade_no: resource.transaction_id,
                 success_at: resource.success_time,
+                // 未传 attach 时微信可能返回空字符串
+                attach: resource.attach.filter(|a| !a.is_empty()),
                 res,
             },
             http_req,
             Some(http_res),
-        )
+        ))
     }
 
+    #[tracing::instrument(skip(self, req), fields(payment_id = %payment_id, refund_id = %req.refund_id))]
     async fn refund(
         &self,
         payment_id: Uuid,
         req: RefundRequest,
-    ) -> (RefundResponse, HttpRequestJson, Option<HttpResponseJson>) {
+    ) -> Result<(RefundResponse, HttpRequestJson, Option<HttpResponseJson>), RefundError> {
         const API_PATH: &str = "/v3/refund/domestic/refunds";
 
-        let body = json!({
+        let mut body = json!({
             "out_trade_no": payment_id.simple().to_string(),
             "out_refund_no": req.refund_id.simple().to_string(),
-            "notify_url": self.refund_notify_url,
+            "notify_url": req.notify_url.as_deref().unwrap_or(&self.refund_notify_url),
             "amount": {
                 "refund": req.amount,
                 "total": req.total,
@@ -269,11 +401,15 @@ impl PaymentServiceProvider for WxPayJsapi {
             }
         });
 
+        if let Some(sub_mchid) = &self.sub_mchid {
+            body["sub_mchid"] = json!(sub_mchid);
+        }
+
         let body_str = serde_json::to_string(&body).unwrap();
 
         let auth_header = get_body_auth_header(
             &self.mchid,
-            self.merchant_cert_private_key.clone(),
+            &self.signing_key,
             &self.merchant_cert_serial_no,
             http::Method::POST,
             API_PATH,
@@ -282,18 +418,33 @@ impl PaymentServiceProvider for WxPayJsapi {
 
         let req_http = self
             .reqwest
-            .post(format!("https://api.mch.weixin.qq.com{API_PATH}"))
+            .post(format!("{}{API_PATH}", self.api_base_url))
             .body(body_str.clone())
             .header("Authorization", auth_header)
             .header("User-Agent", "bokchoy")
             .header("Accept", "application/json")
             .header("Content-Type", "application/json")
-            .build()
-            .unwrap();
+            .build()?;
 
         let http_req = HttpRequestJson::from_reqwest_req(&req_http, body);
 
-        let res = self.reqwest.execute(req_http).await.unwrap();
+        let res = self
+            .reqwest
+            .execute(req_http)
+            .await
+            .inspect_err(|e| tracing::error!(error = %e, "wxpay refund request failed"))?;
+
+        if !res.status().is_success() {
+            let status = res.status().as_u16();
+            let body = res.text().await?;
+
+            tracing::error!(status, body, "wxpay refund request rejected");
+
+            return Err(match WxPayApiError::parse(status, &body) {
+                Some(err) => RefundError::WxPay(err),
+                None => RefundError::Provider { status, body },
+            });
+        }
 
         let (http_res, body_json) = {
             let status = res.status().as_u16();
@@ -304,7 +455,7 @@ impl PaymentServiceProvider for WxPayJsapi {
                 .filter_map(|(k, v)| v.to_str().map(|v| (k.to_string(), v.to_string())).ok())
                 .collect::<Vec<_>>();
 
-            let body = res.json::<serde_json::Value>().await.unwrap();
+            let body = res.json::<serde_json::Value>().await?;
 
             (
                 HttpResponseJson {
@@ -316,103 +467,86 @@ impl PaymentServiceProvider for WxPayJsapi {
             )
         };
 
-        if http_res.status >= 200 && http_res.status < 300 {
-            let refund_no = body_json["refund_id"].as_str().unwrap().to_string();
-            let status = body_json["status"].as_str().unwrap().to_string();
-
-            (
-                RefundResponse {
-                    refund_id: req.refund_id,
-                    provider_refund_no: refund_no,
-                    status,
-                },
-                http_req,
-                Some(http_res),
-            )
-        } else {
-            // Log the error and panic as per instructions to ignore robust error handling for now
-            panic!(
-                "Refund failed: status={}, body={}",
-                http_res.status, body_json
-            );
+        #[derive(Deserialize)]
+        struct RefundApiResponse {
+            refund_id: String,
+            status: String,
         }
+
+        let Ok(body) = serde_json::from_value::<RefundApiResponse>(body_json) else {
+            tracing::error!(body = %http_res.body, "wxpay refund response missing refund_id");
+
+            return Err(RefundError::Provider {
+                status: http_res.status,
+                body: http_res.body.to_string(),
+            });
+        };
+
+        Ok((
+            RefundResponse {
+                refund_id: req.refund_id,
+                provider_refund_no: body.refund_id,
+                status: body.status,
+            },
+            http_req,
+            Some(http_res),
+        ))
     }
 
+    #[tracing::instrument(
+        skip_all,
+        fields(payment_id = tracing::field::Empty, refund_id = tracing::field::Empty)
+    )]
     async fn refund_callback(
         &self,
         req: http::Request<bytes::Bytes>,
-    ) -> (
-        RefundCallbackOutcome,
-        HttpRequestJson,
-        Option<HttpResponseJson>,
-    ) {
+    ) -> Result<
+        (
+            RefundCallbackOutcome,
+            HttpRequestJson,
+            Option<HttpResponseJson>,
+        ),
+        CallbackError,
+    > {
         let http_req = HttpRequestJson::from_http_req(&req);
 
-        let header = req.headers();
+        self.verify_notification(&req)?;
 
-        let Some(Ok(timestamp)) = header.get("Wechatpay-Timestamp").map(HeaderValue::to_str) else {
-            panic!();
-        };
-        let Some(Ok(nonce)) = header.get("Wechatpay-Nonce").map(HeaderValue::to_str) else {
-            panic!();
-        };
-        let Some(Ok(cert_serial)) = header.get("Wechatpay-Serial").map(HeaderValue::to_str) else {
-            panic!();
-        };
-        let Some(Ok(sign)) = header.get("Wechatpay-Signature").map(HeaderValue::to_str) else {
-            panic!();
-        };
+        let body = serde_json::from_slice::<serde_json::Value>(req.body())
+            .map_err(CallbackError::BadBody)?;
 
-        if cert_serial != self.wxpay_public_key_id {
-            // TODO: 平台证书
-            panic!();
-        }
+        check_event_type(&body, REFUND_EVENT_TYPES)?;
 
-        if verify_response(
-            self.wxpay_public_key.clone(),
-            sign,
-            timestamp,
-            nonce,
-            req.body(),
-        )
-        .is_err()
-        {
-            panic!();
-        }
+        check_resource_type(&body)?;
 
-        let Ok(body) = serde_json::from_slice::<serde_json::Value>(req.body()) else {
-            panic!();
-        };
+        let encrypted = serde_json::from_value::<EncryptedResource>(body["resource"].clone())
+            .map_err(CallbackError::BadBody)?;
 
-        if body["resource_type"] != "encrypt-resource" {
-            panic!();
-        }
-
-        let encrypted =
-            serde_json::from_value::<EncryptedResource>(body["resource"].clone()).unwrap();
+        let plain_text = encrypted.decrypt_with_keys(&self.apiv3_keys(), "refund")?;
 
-        let plain_text = encrypted.decrypt(self.apiv3_key.as_bytes()).unwrap();
+        let resource = serde_json::from_str::<PlainRefundResource>(&plain_text)
+            .map_err(CallbackError::BadBody)?;
 
-        let resource = serde_json::from_str::<PlainRefundResource>(&plain_text).unwrap();
+        tracing::Span::current()
+            .record("payment_id", tracing::field::display(resource.out_trade_no))
+            .record("refund_id", tracing::field::display(resource.out_refund_no));
 
-        if resource.mchid != self.mchid {
-            panic!();
+        if resource.mchid != self.mchid || resource.sub_mchid != self.sub_mchid {
+            return Err(CallbackError::MerchantMismatch(resource.mchid));
         }
 
         let status = match resource.refund_status.as_str() {
             "SUCCESS" => RefundStatus::Success,
-            "CLOSED" | "ABNORMAL" => RefundStatus::Failed, // Simplified mapping
-            _ => RefundStatus::Pending,                    // Should not happen in callback usually
+            "CLOSED" => RefundStatus::Failed,
+            "ABNORMAL" => RefundStatus::Abnormal,
+            _ => RefundStatus::Pending, // Should not happen in callback usually
         };
 
-        let res = http::Response::builder()
-            .header("Content-Type", "application/json")
-            .body(json!({}).to_string())
-            .unwrap();
+        let res = ack_response();
 
         let http_res = HttpResponseJson::from_http_res(&res);
 
-        (
+        Ok((
             RefundCallbackOutcome {
                 refund_id: resource.out_refund_no,
                 provider_refund_no: resource.refund_id,
@@ -422,7 +556,7 @@ impl PaymentServiceProvider for WxPayJsapi {
             },
             http_req,
             Some(http_res),
-        )
+        ))
     }
 }
 
@@ -434,8 +568,21 @@ pub struct EncryptedResource {
     pub associated_data: String,
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DecryptError {
+    #[error("associated_data {actual:?} does not match expected {expected:?}")]
+    AadMismatch { expected: String, actual: String },
+
+    #[error("failed to decrypt resource")]
+    Decrypt,
+}
+
 impl EncryptedResource {
     pub fn decrypt(&self, key: &[u8]) -> Result<String, ()> {
+        if key.len() != 32 || self.nonce.len() != 12 {
+            return Err(());
+        }
+
         let key = Key::<Aes256Gcm>::from_slice(key);
         let cipher = Aes256Gcm::new(key);
 
@@ -456,25 +603,458 @@ impl EncryptedResource {
 
         String::from_utf8(data).map_err(|_| ())
     }
+
+    /// 校验 `associated_data` 为预期值（如 `transaction`、`refund`）后，
+    /// 依次尝试 `keys` 解密，用于 APIv3 密钥轮换期间新旧密钥并存
+    pub fn decrypt_with_keys(
+        &self,
+        keys: &[&[u8]],
+        expected_aad: &str,
+    ) -> Result<String, DecryptError> {
+        if self.associated_data != expected_aad {
+            return Err(DecryptError::AadMismatch {
+                expected: expected_aad.to_string(),
+                actual: self.associated_data.clone(),
+            });
+        }
+
+        keys.iter()
+            .find_map(|key| self.decrypt(key).ok())
+            .ok_or(DecryptError::Decrypt)
+    }
 }
 
 #[derive(Deserialize)]
 struct PlainResource {
+    #[serde(alias = "sp_appid")]
     appid: String,
+    #[serde(alias = "sp_mchid")]
     mchid: String,
+    sub_mchid: Option<String>,
     out_trade_no: Uuid,
     transaction_id: String,
     trade_state: String,
     #[serde(with = "time::serde::rfc3339")]
     success_time: OffsetDateTime,
+    attach: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct PlainRefundResource {
+    #[serde(alias = "sp_mchid")]
     mchid: String,
+    sub_mchid: Option<String>,
+    out_trade_no: Uuid,
     out_refund_no: Uuid,
     refund_id: String,
     refund_status: String,
     #[serde(with = "time::serde::rfc3339::option")]
     success_time: Option<OffsetDateTime>,
 }
+
+/// 各回调接口接受的事件类型
+const PAY_EVENT_TYPES: &[&str] = &["TRANSACTION.SUCCESS"];
+const REFUND_EVENT_TYPES: &[&str] = &["REFUND.SUCCESS", "REFUND.ABNORMAL", "REFUND.CLOSED"];
+
+/// 签名只覆盖时间戳、随机串与 body，不区分接口，需校验事件类型避免通知被转发到其他回调接口
+fn check_event_type(body: &serde_json::Value, accepted: &[&str]) -> Result<(), CallbackError> {
+    let event_type = body["event_type"].as_str().unwrap_or_default();
+
+    if !accepted.contains(&event_type) {
+        return Err(CallbackError::UnexpectedEventType(event_type.to_string()));
+    }
+
+    Ok(())
+}
+
+/// 回调处理成功时返回给微信的应答
+fn ack_response() -> http::Response<String> {
+    let mut res = http::Response::new(json!({}).to_string());
+    res.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+
+    res
+}
+
+fn check_resource_type(body: &serde_json::Value) -> Result<(), CallbackError> {
+    let resource_type = body["resource_type"].as_str().unwrap_or_default();
+
+    if resource_type != "encrypt-resource" {
+        return Err(CallbackError::UnexpectedResourceType(
+            resource_type.to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// 重复下单时微信返回的错误码映射为单独的错误，调用方可据此改为查询支付状态
+fn prepay_error(status: u16, body: String) -> PayError {
+    let Some(err) = WxPayApiError::parse(status, &body) else {
+        return PayError::Provider { status, body };
+    };
+
+    match err.code.as_str() {
+        "ORDERPAID" => PayError::OrderPaid,
+        "ORDER_CLOSED" => PayError::OrderClosed,
+        "OUT_TRADE_NO_USED" => PayError::OutTradeNoUsed,
+        _ => PayError::WxPay(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{
+        Aes256Gcm, Key, KeyInit as _, Nonce,
+        aead::{Aead as _, Payload},
+    };
+
+    use super::*;
+    use crate::psp::GoodsDetail;
+
+    const KEY: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+    const NONCE: &str = "0123456789ab";
+
+    fn encrypt(plain_text: &str, aad: &str) -> EncryptedResource {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(KEY));
+
+        let msg = cipher
+            .encrypt(
+                Nonce::from_slice(NONCE.as_bytes()),
+                Payload {
+                    msg: plain_text.as_bytes(),
+                    aad: aad.as_bytes(),
+                },
+            )
+            .expect("encrypt");
+
+        use base64::prelude::*;
+        EncryptedResource {
+            ciphertext: BASE64_STANDARD.encode(msg),
+            nonce: NONCE.to_string(),
+            associated_data: aad.to_string(),
+        }
+    }
+
+    #[test]
+    fn decrypt_with_aad() {
+        let resource = encrypt("{}", "transaction");
+
+        assert_eq!(resource.decrypt(KEY).as_deref(), Ok("{}"));
+        assert_eq!(
+            resource
+                .decrypt_with_keys(&[KEY], "transaction")
+                .ok()
+                .as_deref(),
+            Some("{}")
+        );
+        assert!(matches!(
+            resource.decrypt_with_keys(&[KEY], "refund"),
+            Err(DecryptError::AadMismatch { .. })
+        ));
+        assert!(matches!(
+            resource.decrypt_with_keys(&[b"short key"], "transaction"),
+            Err(DecryptError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn decrypt_falls_back_to_secondary_key() {
+        let resource = encrypt("{}", "refund");
+        let old_key: &[u8] = b"fedcba9876543210fedcba9876543210";
+
+        assert_eq!(
+            resource
+                .decrypt_with_keys(&[old_key, KEY], "refund")
+                .ok()
+                .as_deref(),
+            Some("{}")
+        );
+        assert!(matches!(
+            resource.decrypt_with_keys(&[old_key], "refund"),
+            Err(DecryptError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn prepay_error_maps_duplicate_order_codes() {
+        let err = prepay_error(
+            400,
+            r#"{"code":"ORDERPAID","message":"该订单已支付"}"#.into(),
+        );
+        assert!(matches!(err, PayError::OrderPaid));
+
+        let err = prepay_error(400, r#"{"code":"PARAM_ERROR","message":"参数错误"}"#.into());
+        assert!(
+            matches!(err, PayError::WxPay(WxPayApiError { status: 400, ref code, .. }) if code == "PARAM_ERROR")
+        );
+
+        let err = prepay_error(502, "Bad Gateway".into());
+        assert!(matches!(err, PayError::Provider { status: 502, .. }));
+    }
+
+    #[tokio::test]
+    async fn pay_against_mock_server() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0; 64 * 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..n]).into_owned();
+
+            let body = r#"{"prepay_id":"wx_prepay"}"#;
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(res.as_bytes()).await.unwrap();
+
+            req
+        });
+
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let provider = WxPayJsapi::new(
+            ("appid".into(), "mchid".into()),
+            "https://example.com/pay".into(),
+            "https://example.com/refund".into(),
+            "serial".into(),
+            key.clone(),
+            "pub_key_id".into(),
+            key.to_public_key(),
+            String::from_utf8(KEY.to_vec()).unwrap(),
+        )
+        .api_base_url(format!("http://{addr}"));
+
+        let err = provider
+            .pay(
+                Uuid::now_v7(),
+                PayRequest {
+                    biz_id: Uuid::now_v7(),
+                    amount: 100,
+                    description: "test".into(),
+                    extras: Default::default(),
+                    idempotency_key: None,
+                    attach: None,
+                    goods_detail: vec![],
+                    notify_url: None,
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, PayError::MissingExtra("openid")));
+
+        let (res, _, _) = provider
+            .pay(
+                Uuid::now_v7(),
+                PayRequest {
+                    biz_id: Uuid::now_v7(),
+                    amount: 100,
+                    description: "test".into(),
+                    extras: [("openid".to_string(), "openid".to_string())].into(),
+                    idempotency_key: None,
+                    attach: Some("order".into()),
+                    goods_detail: vec![GoodsDetail {
+                        merchant_goods_id: "goods".into(),
+                        wechatpay_goods_id: None,
+                        goods_name: None,
+                        quantity: 1,
+                        unit_price: 100,
+                    }],
+                    notify_url: Some("https://example.com/tenants/1/pay".into()),
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.prepay_id.as_deref(), Some("wx_prepay"));
+        assert_eq!(res.provider_params["package"], "prepay_id=wx_prepay");
+
+        let req = server.await.unwrap();
+        assert!(req.starts_with("POST /v3/pay/transactions/jsapi "));
+        assert!(req.contains(r#""attach":"order""#));
+        assert!(req.contains(r#""notify_url":"https://example.com/tenants/1/pay""#));
+        assert!(req.contains(
+            r#""detail":{"goods_detail":[{"merchant_goods_id":"goods","quantity":1,"unit_price":100}]}"#
+        ));
+    }
+
+    /// 以同一把 RSA 密钥作为商户私钥与微信支付公钥，便于在测试中自行签名回调
+    fn callback_provider() -> (WxPayJsapi, rsa::RsaPrivateKey) {
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let provider = WxPayJsapi::new(
+            ("appid".into(), "mchid".into()),
+            "https://example.com/pay".into(),
+            "https://example.com/refund".into(),
+            "serial".into(),
+            key.clone(),
+            "pub_key_id".into(),
+            key.to_public_key(),
+            String::from_utf8(KEY.to_vec()).unwrap(),
+        );
+
+        (provider, key)
+    }
+
+    fn notification(event_type: &str, plain_text: &str, aad: &str) -> String {
+        let resource = encrypt(plain_text, aad);
+
+        json!({
+            "event_type": event_type,
+            "resource_type": "encrypt-resource",
+            "resource": {
+                "ciphertext": resource.ciphertext,
+                "nonce": resource.nonce,
+                "associated_data": resource.associated_data,
+            },
+        })
+        .to_string()
+    }
+
+    fn signed_callback(key: &rsa::RsaPrivateKey, body: String) -> http::Request<bytes::Bytes> {
+        use rsa::signature::{SignatureEncoding as _, Signer as _};
+
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp().to_string();
+
+        let sign = SigningKey::<Sha256>::new(key.clone())
+            .sign(format!("{timestamp}\n{NONCE}\n{body}\n").as_bytes());
+
+        use base64::prelude::*;
+        http::Request::builder()
+            .header("Wechatpay-Timestamp", &timestamp)
+            .header("Wechatpay-Nonce", NONCE)
+            .header("Wechatpay-Serial", "pub_key_id")
+            .header(
+                "Wechatpay-Signature",
+                BASE64_STANDARD.encode(sign.to_bytes()),
+            )
+            .body(bytes::Bytes::from(body))
+            .unwrap()
+    }
+
+    fn transaction(mchid: &str, trade_state: &str) -> String {
+        json!({
+            "appid": "appid",
+            "mchid": mchid,
+            "out_trade_no": Uuid::now_v7().simple().to_string(),
+            "transaction_id": "4200000000",
+            "trade_state": trade_state,
+            "success_time": "2025-01-01T00:00:00+08:00",
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn refund_callback_rejects_payment_event() {
+        let (provider, key) = callback_provider();
+
+        let body = notification("TRANSACTION.SUCCESS", "{}", "transaction");
+
+        let err = provider
+            .refund_callback(signed_callback(&key, body))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            CallbackError::UnexpectedEventType(t) if t == "TRANSACTION.SUCCESS"
+        ));
+    }
+
+    #[tokio::test]
+    async fn pay_callback_rejects_malformed_body() {
+        let (provider, key) = callback_provider();
+
+        let err = provider
+            .pay_callback(signed_callback(&key, "not json".into()))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, CallbackError::BadBody(_)));
+    }
+
+    #[tokio::test]
+    async fn pay_callback_rejects_malformed_resource() {
+        let (provider, key) = callback_provider();
+
+        let body = notification("TRANSACTION.SUCCESS", r#"{"appid":1}"#, "transaction");
+
+        let err = provider
+            .pay_callback(signed_callback(&key, body))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, CallbackError::BadBody(_)));
+    }
+
+    #[tokio::test]
+    async fn pay_callback_rejects_plain_resource() {
+        let (provider, key) = callback_provider();
+
+        let body = json!({
+            "event_type": "TRANSACTION.SUCCESS",
+            "resource_type": "plain-resource",
+            "resource": {},
+        })
+        .to_string();
+
+        let err = provider
+            .pay_callback(signed_callback(&key, body))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            CallbackError::UnexpectedResourceType(t) if t == "plain-resource"
+        ));
+    }
+
+    #[tokio::test]
+    async fn pay_callback_rejects_other_merchant() {
+        let (provider, key) = callback_provider();
+
+        let body = notification(
+            "TRANSACTION.SUCCESS",
+            &transaction("other_mchid", "SUCCESS"),
+            "transaction",
+        );
+
+        let err = provider
+            .pay_callback(signed_callback(&key, body))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            CallbackError::MerchantMismatch(m) if m == "other_mchid"
+        ));
+    }
+
+    #[tokio::test]
+    async fn pay_callback_rejects_unpaid_trade_state() {
+        let (provider, key) = callback_provider();
+
+        let body = notification(
+            "TRANSACTION.SUCCESS",
+            &transaction("mchid", "NOTPAY"),
+            "transaction",
+        );
+
+        let err = provider
+            .pay_callback(signed_callback(&key, body))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            CallbackError::UnexpectedTradeState(s) if s == "NOTPAY"
+        ));
+    }
+}
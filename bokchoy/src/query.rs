@@ -0,0 +1,178 @@
+use sqlx::{PgConnection, Postgres, QueryBuilder};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{PaymentService, RefundRecord, RefundStatus};
+
+#[derive(Debug, Clone)]
+pub struct RefundQuery {
+    pub status: Option<RefundStatus>,
+    pub payment_id: Option<Uuid>,
+    pub biz_id: Option<Uuid>,
+    pub created_after: Option<OffsetDateTime>,
+    pub created_before: Option<OffsetDateTime>,
+    pub offset: i64,
+    pub limit: Option<i64>,
+}
+
+impl Default for RefundQuery {
+    fn default() -> Self {
+        Self {
+            status: None,
+            payment_id: None,
+            biz_id: None,
+            created_after: None,
+            created_before: None,
+            offset: 0,
+            limit: Some(20),
+        }
+    }
+}
+
+impl RefundQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(mut self, status: RefundStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn payment_id(mut self, payment_id: Uuid) -> Self {
+        self.payment_id = Some(payment_id);
+        self
+    }
+
+    pub fn biz_id(mut self, biz_id: Uuid) -> Self {
+        self.biz_id = Some(biz_id);
+        self
+    }
+
+    /// 创建时间在 [after, before) 内，任一端为 `None` 时不限制该端
+    pub fn created_between(
+        mut self,
+        after: Option<OffsetDateTime>,
+        before: Option<OffsetDateTime>,
+    ) -> Self {
+        self.created_after = after;
+        self.created_before = before;
+        self
+    }
+
+    pub fn page(mut self, page: i64, page_size: i64) -> Self {
+        let page_size = page_size.max(1);
+        self.offset = (page.max(1) - 1).saturating_mul(page_size);
+        self.limit = Some(page_size);
+        self
+    }
+
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn limit(mut self, limit: Option<i64>) -> Self {
+        self.limit = limit;
+        self
+    }
+}
+
+fn apply_filters(builder: &mut QueryBuilder<'_, Postgres>, query: &RefundQuery) {
+    if let Some(status) = query.status {
+        builder.push(" AND status = ");
+        builder.push_bind(status);
+    }
+    if let Some(payment_id) = query.payment_id {
+        builder.push(" AND payment_id = ");
+        builder.push_bind(payment_id);
+    }
+    if let Some(biz_id) = query.biz_id {
+        builder.push(" AND biz_id = ");
+        builder.push_bind(biz_id);
+    }
+    if let Some(after) = query.created_after {
+        builder.push(" AND created_at >= ");
+        builder.push_bind(after);
+    }
+    if let Some(before) = query.created_before {
+        builder.push(" AND created_at < ");
+        builder.push_bind(before);
+    }
+}
+
+impl PaymentService {
+    /// 统计符合过滤条件的退款总数，忽略 limit/offset
+    pub async fn count_refunds(
+        &self,
+        query: &RefundQuery,
+        conn: &mut PgConnection,
+    ) -> Result<i64, sqlx::Error> {
+        let mut builder = QueryBuilder::new("SELECT count(*) FROM bokchoy.refunds WHERE 1=1");
+
+        apply_filters(&mut builder, query);
+
+        builder
+            .build_query_scalar::<i64>()
+            .fetch_one(&mut *conn)
+            .await
+    }
+
+    pub async fn query_refunds(
+        &self,
+        query: RefundQuery,
+        conn: &mut PgConnection,
+    ) -> Result<Vec<RefundRecord>, sqlx::Error> {
+        let mut builder = QueryBuilder::new(
+            r#"
+            SELECT
+                id, payment_id, biz_id, provider_refund_no,
+                amount, reason, status
+            FROM bokchoy.refunds
+            WHERE 1=1
+            "#,
+        );
+
+        apply_filters(&mut builder, &query);
+
+        builder.push(" ORDER BY created_at DESC");
+
+        // Postgres 不接受负数的 LIMIT，直接设置字段时也不应产生运行时错误
+        if let Some(limit) = query.limit {
+            builder.push(" LIMIT ");
+            builder.push_bind(limit.max(0));
+        }
+        if query.offset > 0 {
+            builder.push(" OFFSET ");
+            builder.push_bind(query.offset);
+        }
+
+        builder
+            .build_query_as::<RefundRecord>()
+            .fetch_all(&mut *conn)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_clamps_out_of_range_values() {
+        let query = RefundQuery::new().page(3, 20);
+        assert_eq!((query.offset, query.limit), (40, Some(20)));
+
+        let query = RefundQuery::new().page(0, 20);
+        assert_eq!((query.offset, query.limit), (0, Some(20)));
+
+        let query = RefundQuery::new().page(2, 0);
+        assert_eq!((query.offset, query.limit), (1, Some(1)));
+
+        let query = RefundQuery::new().page(-5, -10);
+        assert_eq!((query.offset, query.limit), (0, Some(1)));
+
+        let query = RefundQuery::new().page(i64::MAX, i64::MAX);
+        assert_eq!((query.offset, query.limit), (i64::MAX, Some(i64::MAX)));
+    }
+}
@@ -1,38 +1,59 @@
-use rand::Rng as _;
+use std::collections::BTreeMap;
+
+use rand::Rng;
 use rsa::{
-    RsaPrivateKey, RsaPublicKey,
     pkcs1v15::{Signature, SigningKey, VerifyingKey},
     signature::{RandomizedSigner as _, SignatureEncoding as _, Verifier as _},
 };
 use sha2::Sha256;
 use time::OffsetDateTime;
 
+/// 随机字符串长度，微信支付要求不超过 32 个字符
+pub const NONCE_LEN: usize = 30;
+
+/// 生成 [`NONCE_LEN`] 位的字母数字随机串，测试时可传入固定种子的 rng
+pub fn generate_nonce(rng: &mut impl Rng) -> String {
+    (0..NONCE_LEN)
+        .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+        .collect()
+}
+
 /// https://pay.weixin.qq.com/doc/v3/merchant/4012365336
 pub fn get_body_auth_header(
     mchid: &str,
-    key: RsaPrivateKey,
+    key: &SigningKey<Sha256>,
     serial: &str,
     method: http::Method,
     uri: &str,
     body: &str,
 ) -> String {
-    let nonce = {
-        let mut rng = rand::thread_rng();
-        (0..30)
-            .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
-            .collect::<String>()
-    };
+    let nonce = generate_nonce(&mut rand::thread_rng());
     let timestamp = OffsetDateTime::now_utc().unix_timestamp();
 
-    let sign = get_sign(key, method, uri, body, &nonce, timestamp);
+    get_body_auth_header_inner(mchid, key, serial, method, uri, body, &nonce, timestamp)
+}
+
+/// 随机串与时间戳由调用方传入，测试时可得到固定的签名
+#[allow(clippy::too_many_arguments)]
+fn get_body_auth_header_inner(
+    mchid: &str,
+    key: &SigningKey<Sha256>,
+    serial: &str,
+    method: http::Method,
+    uri: &str,
+    body: &str,
+    nonce: &str,
+    timestamp: i64,
+) -> String {
+    let sign = get_sign(key, method, uri, body, nonce, timestamp);
 
     format!(
-        r#"WECHATPAY2-SHA256-RSA2048 mchid="{mchid}",nonce_str="{nonce}",signature="{sign}",timestamp="{timestamp}",serial_no="{serial}"""#
+        r#"WECHATPAY2-SHA256-RSA2048 mchid="{mchid}",nonce_str="{nonce}",signature="{sign}",timestamp="{timestamp}",serial_no="{serial}""#
     )
 }
 
 fn get_sign(
-    key: RsaPrivateKey,
+    key: &SigningKey<Sha256>,
     method: http::Method,
     uri: &str,
     body: &str,
@@ -51,30 +72,22 @@ fn get_sign(
     BASE64_STANDARD.encode(sign.to_bytes())
 }
 
-fn sign_sha256_rsa(key: RsaPrivateKey, data: &[u8]) -> rsa::pkcs1v15::Signature {
-    let signing_key = SigningKey::<Sha256>::new(key);
+fn sign_sha256_rsa(key: &SigningKey<Sha256>, data: &[u8]) -> Signature {
     let mut rng = rand::thread_rng();
 
-    signing_key.sign_with_rng(&mut rng, data)
+    key.sign_with_rng(&mut rng, data)
 }
 
-fn verify_sha256_rsa(key: RsaPublicKey, data: &[u8], sign: &[u8]) -> Result<(), ()> {
-    let verifying_key = VerifyingKey::<Sha256>::new(key);
-
+fn verify_sha256_rsa(key: &VerifyingKey<Sha256>, data: &[u8], sign: &[u8]) -> Result<(), ()> {
     let sign = Signature::try_from(sign).map_err(|_| ())?;
 
-    verifying_key.verify(data, &sign).map_err(|_| ())
+    key.verify(data, &sign).map_err(|_| ())
 }
 
 /// 计算“小程序调起支付签名”
 /// https://pay.weixin.qq.com/doc/v3/merchant/4012365341
-pub fn pay_sign(appid: &str, key: RsaPrivateKey, prepay_id: &str) -> (i64, String, String) {
-    let nonce = {
-        let mut rng = rand::thread_rng();
-        (0..30)
-            .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
-            .collect::<String>()
-    };
+pub fn pay_sign(appid: &str, key: &SigningKey<Sha256>, prepay_id: &str) -> (i64, String, String) {
+    let nonce = generate_nonce(&mut rand::thread_rng());
 
     let timestamp = OffsetDateTime::now_utc().unix_timestamp();
 
@@ -84,7 +97,7 @@ pub fn pay_sign(appid: &str, key: RsaPrivateKey, prepay_id: &str) -> (i64, Strin
 }
 
 fn pay_sign_inner(
-    key: RsaPrivateKey,
+    key: &SigningKey<Sha256>,
     app_id: &str,
     nonce: &str,
     timestamp: i64,
@@ -99,7 +112,7 @@ fn pay_sign_inner(
 }
 
 pub fn verify_response(
-    key: RsaPublicKey,
+    key: &VerifyingKey<Sha256>,
     sign: &str,
     timestamp: &str,
     nonce: &str,
@@ -113,14 +126,71 @@ pub fn verify_response(
     verify_sha256_rsa(key, str_to_sign.as_bytes(), &sign)
 }
 
+/// 支付宝待签名字符串：除 `exclude` 外的非空参数按参数名升序以 `k=v` 用 `&` 连接，值不做编码
+fn alipay_sign_content(params: &BTreeMap<String, String>, exclude: &[&str]) -> String {
+    params
+        .iter()
+        .filter(|(k, v)| !v.is_empty() && !exclude.contains(&k.as_str()))
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// 支付宝请求签名（RSA2），`sign_type` 参与签名
+pub fn alipay_sign(key: &SigningKey<Sha256>, params: &BTreeMap<String, String>) -> String {
+    let content = alipay_sign_content(params, &["sign"]);
+
+    let sign = sign_sha256_rsa(key, content.as_bytes());
+
+    use base64::prelude::*;
+    BASE64_STANDARD.encode(sign.to_bytes())
+}
+
+/// 校验支付宝异步通知的签名（RSA2），`sign` 与 `sign_type` 均不参与签名
+pub fn alipay_verify(
+    key: &VerifyingKey<Sha256>,
+    params: &BTreeMap<String, String>,
+) -> Result<(), ()> {
+    let sign = params.get("sign").ok_or(())?;
+    let content = alipay_sign_content(params, &["sign", "sign_type"]);
+
+    alipay_verify_content(key, &content, sign)
+}
+
+/// 校验支付宝同步响应的签名（RSA2），签名内容为响应中 `{method}_response` 的原始 JSON 文本
+pub fn alipay_verify_content(
+    key: &VerifyingKey<Sha256>,
+    content: &str,
+    sign: &str,
+) -> Result<(), ()> {
+    use base64::prelude::*;
+    let sign = BASE64_STANDARD.decode(sign).map_err(|_| ())?;
+
+    verify_sha256_rsa(key, content.as_bytes(), &sign)
+}
+
+/// 校验回调时间戳与 `now` 的偏差不超过 `window`，防止重放
+pub fn verify_timestamp(
+    timestamp: &str,
+    now: OffsetDateTime,
+    window: time::Duration,
+) -> Result<(), ()> {
+    let timestamp = timestamp.parse::<i64>().map_err(|_| ())?;
+    let timestamp = OffsetDateTime::from_unix_timestamp(timestamp).map_err(|_| ())?;
+
+    if (now - timestamp).abs() > window {
+        return Err(());
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use rsa::{
-        pkcs1::{DecodeRsaPrivateKey as _, DecodeRsaPublicKey as _},
-        pkcs8::{DecodePrivateKey as _, DecodePublicKey as _},
-    };
+    use rsa::RsaPrivateKey;
 
     use super::*;
+    use crate::keys::{load_private_key, load_public_key};
 
     #[test]
     fn get_pay_sign() {
@@ -158,14 +228,9 @@ OdhCkaIGBCfRnlECRK8UyQ==
         const PREPAY_ID: &str = "wx201410272009395522657a690389285100";
         const RESULT: &str = "mI35pfNEQV6777ke/1T+LJLQDNTm7yeoUJH+j/adPGhmCCi0PbgkvYQTRcXH0uibcLVtvFLdGLpmoYO9FV6lBBsTAjuhh5YOvQi0e2g3e0yytitiNET9FEuqM0pjnKfRW4K6LIZDdbWJv9KhZUx3DrJa5TL7OJ7VdADVivxVySIlPVKjGwuCXzuXSJes0UcILgWQUMyha5/3nYofuHtS7r+KYyMuxD+oJ9VM1Qdxk4UIG59CP5Y3wtYIFybyF3bdu1caHTRRX+DLyMXyYA/IrTmiW01c4RPjpHBX5Dk1sZyY1zVsWNsvMHr2e1NTWtBxKJ+qk5N61J7caYoepHFaxw==";
 
-        let pem = pem::parse(TEST_PEM).expect("parse test pem");
-        let key = match pem.tag() {
-            "RSA PRIVATE KEY" => RsaPrivateKey::from_pkcs1_der(pem.contents()).expect("decode key"),
-            "PRIVATE KEY" => RsaPrivateKey::from_pkcs8_der(pem.contents()).expect("decode key"),
-            _ => panic!("Unsupported key type: {}", pem.tag()),
-        };
+        let key = load_private_key(TEST_PEM.as_bytes()).expect("decode key");
 
-        let sign = pay_sign_inner(key, APP_ID, NONCE, TIMESTAMP, PREPAY_ID);
+        let sign = pay_sign_inner(&SigningKey::new(key), APP_ID, NONCE, TIMESTAMP, PREPAY_ID);
 
         assert_eq!(sign, RESULT);
     }
@@ -205,15 +270,10 @@ OdhCkaIGBCfRnlECRK8UyQ==
         const TEST_BODY: &str = r#"{"appid":"wxd678efh567hg6787","mchid":"1900007291","description":"Image形象店-深圳腾大-QQ公仔","out_trade_no":"1217752501201407033233368018","notify_url":"https://www.weixin.qq.com/wxpay/pay.php","amount":{"total":100,"currency":"CNY"},"payer":{"openid":"oUpF8uMuAJO_M2pxb1Q9zNjWeS6o"}}"#;
         const TEST_RESULT: &str = "jnks4dlrPw3ZX+ozVvSK39oa0t7OMBsg83BHAwd8BRdUFiVaQNTLTvci+wURgP1OQBbKYhFGvt7iqYpDSTQkp7Uq1sltaQKyncCyrA1g88m5bsKERQfPyT0ahSwKTYJ1CAn9QiJuSJRq1QsQs07eehbU/k9BCS51jTyc1Jpsi2H77HF9f/BnjXAOP3/sPObg6V5Ee4EzwLox684hhuMuIwHo7D8KFk3LIHOKDcNI4It1aCXydFWNpNK+SG86VUDe5kwoDpw4Ulqfu9z8OFDGbDs9TCxEv8iqQzbpxOlEVoOe2kalSYM5kApQb3nZcxdUtoE0liJGW3RGUNE0t4v01A==";
 
-        let pem = pem::parse(TEST_PEM).expect("parse test pem");
-        let key = match pem.tag() {
-            "RSA PRIVATE KEY" => RsaPrivateKey::from_pkcs1_der(pem.contents()).expect("decode key"),
-            "PRIVATE KEY" => RsaPrivateKey::from_pkcs8_der(pem.contents()).expect("decode key"),
-            _ => panic!("Unsupported key type: {}", pem.tag()),
-        };
+        let key = load_private_key(TEST_PEM.as_bytes()).expect("decode key");
 
         let sign = get_sign(
-            key,
+            &SigningKey::new(key),
             http::Method::POST,
             "/v3/pay/transactions/jsapi",
             TEST_BODY,
@@ -240,18 +300,127 @@ WwIDAQAB
         const NONCE: &str = "d824f2e086d3c1df967785d13fcd22ef";
         const BODY: &str = r#"{"code_url":"weixin://wxpay/bizpayurl?pr=JyC91EIz1"}"#;
 
-        let pem = pem::parse(PEM).expect("parse wechat pay public pem");
+        let key = load_public_key(PEM.as_bytes()).expect("decode public pem");
+
+        assert!(
+            super::verify_response(
+                &VerifyingKey::new(key),
+                SIGN,
+                TIMESTAMP,
+                NONCE,
+                BODY.as_bytes()
+            )
+            .is_ok()
+        );
+    }
 
-        let key = match pem.tag() {
-            "RSA PUBLIC KEY" => {
-                RsaPublicKey::from_pkcs1_der(pem.contents()).expect("decode public pem")
-            }
-            "PUBLIC KEY" => {
-                RsaPublicKey::from_public_key_der(pem.contents()).expect("decode public pem")
-            }
-            _ => panic!("Unsupported pem key type: {}", pem.tag()),
+    #[test]
+    fn body_auth_header_is_reproducible() {
+        use rand::{SeedableRng as _, rngs::StdRng};
+
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let signing_key = SigningKey::<Sha256>::new(key.clone());
+
+        const BODY: &str = r#"{"appid":"wxd678efh567hg6787"}"#;
+        let nonce = generate_nonce(&mut StdRng::seed_from_u64(42));
+
+        let header = || {
+            get_body_auth_header_inner(
+                "1900009191",
+                &signing_key,
+                "serial",
+                http::Method::POST,
+                "/v3/pay/transactions/jsapi",
+                BODY,
+                &nonce,
+                1554208460,
+            )
         };
 
-        assert!(super::verify_response(key, SIGN, TIMESTAMP, NONCE, BODY.as_bytes()).is_ok());
+        let a = header();
+        assert_eq!(a, header());
+
+        let sign = a
+            .split_once(r#"signature=""#)
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(sign, _)| sign)
+            .unwrap();
+        assert_eq!(
+            a,
+            format!(
+                r#"WECHATPAY2-SHA256-RSA2048 mchid="1900009191",nonce_str="{nonce}",signature="{sign}",timestamp="1554208460",serial_no="serial""#
+            )
+        );
+
+        use base64::prelude::*;
+        let sign = BASE64_STANDARD.decode(sign).unwrap();
+        let content = format!("POST\n/v3/pay/transactions/jsapi\n1554208460\n{nonce}\n{BODY}\n");
+        assert!(
+            verify_sha256_rsa(
+                &VerifyingKey::new(key.to_public_key()),
+                content.as_bytes(),
+                &sign
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn nonce_is_reproducible_with_seeded_rng() {
+        use rand::{SeedableRng as _, rngs::StdRng};
+
+        let a = generate_nonce(&mut StdRng::seed_from_u64(42));
+        let b = generate_nonce(&mut StdRng::seed_from_u64(42));
+
+        assert_eq!(a, b);
+        assert_eq!(a.len(), NONCE_LEN);
+        assert!(a.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[test]
+    fn alipay_sign_roundtrip() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let verifying_key = VerifyingKey::new(key.to_public_key());
+
+        let mut params = BTreeMap::from(
+            [
+                ("app_id", "2014072300007148"),
+                ("method", "alipay.trade.create"),
+                ("charset", "utf-8"),
+                ("sign_type", "RSA2"),
+                ("biz_content", r#"{"out_trade_no":"1"}"#),
+                ("empty", ""),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        assert_eq!(
+            alipay_sign_content(&params, &["sign"]),
+            r#"app_id=2014072300007148&biz_content={"out_trade_no":"1"}&charset=utf-8&method=alipay.trade.create&sign_type=RSA2"#
+        );
+
+        // 通知验签时 sign_type 不参与签名，这里按通知的规则重新签名
+        let content = alipay_sign_content(&params, &["sign", "sign_type"]);
+        let sign = sign_sha256_rsa(&SigningKey::new(key), content.as_bytes());
+
+        use base64::prelude::*;
+        params.insert("sign".into(), BASE64_STANDARD.encode(sign.to_bytes()));
+        assert!(alipay_verify(&verifying_key, &params).is_ok());
+
+        params.insert("app_id".into(), "2014072300007149".into());
+        assert!(alipay_verify(&verifying_key, &params).is_err());
+    }
+
+    #[test]
+    fn verify_timestamp_window() {
+        let now = OffsetDateTime::from_unix_timestamp(1722850421).unwrap();
+        let window = time::Duration::minutes(5);
+
+        assert!(super::verify_timestamp("1722850421", now, window).is_ok());
+        assert!(super::verify_timestamp("1722850121", now, window).is_ok());
+        assert!(super::verify_timestamp("1722850720", now, window).is_ok());
+        assert!(super::verify_timestamp("1722850120", now, window).is_err());
+        assert!(super::verify_timestamp("1722850722", now, window).is_err());
+        assert!(super::verify_timestamp("not a number", now, window).is_err());
     }
 }
@@ -0,0 +1,33 @@
+/// 数据库中的枚举值无法识别，错误信息中的类型名取自 `T`，避免复制粘贴时写错
+#[derive(Debug, thiserror::Error)]
+#[error("invalid {type_name} value: {value}")]
+pub struct InvalidEnumValue {
+    pub type_name: &'static str,
+    pub value: i16,
+}
+
+impl InvalidEnumValue {
+    pub fn new<T>(value: i16) -> Self {
+        let type_name = std::any::type_name::<T>();
+
+        Self {
+            type_name: type_name.rsplit("::").next().unwrap_or(type_name),
+            value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum OrderStatus {}
+
+    #[test]
+    fn invalid_enum_value_names_type() {
+        assert_eq!(
+            InvalidEnumValue::new::<OrderStatus>(7).to_string(),
+            "invalid OrderStatus value: 7"
+        );
+    }
+}
@@ -0,0 +1,7 @@
+//! bokchoy、jidan 等 crate 共用的基础类型
+
+mod enum_value;
+mod money;
+
+pub use enum_value::InvalidEnumValue;
+pub use money::Money;
@@ -1,12 +1,17 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::{FromRow, PgConnection};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
 pub mod migration;
+#[cfg(feature = "bokchoy")]
+mod payment;
 mod query;
-pub use query::OrderQuery;
+pub use jichu::{InvalidEnumValue, Money};
+#[cfg(feature = "bokchoy")]
+pub use payment::{PaidOrder, PayOrderError};
+pub use query::{OrderQuery, OrderSort};
 
 #[derive(Debug, FromRow)]
 pub struct OrderSummary {
@@ -16,23 +21,33 @@ pub struct OrderSummary {
     pub channel_no: Option<String>,
     pub status: OrderStatus,
 
-    pub total_items_amount: i64,
-    pub payable_amount: i64,
-    pub paid_amount: i64,
-    pub refunded_amount: i64,
+    pub total_items_amount: Money,
+    pub payable_amount: Money,
+    pub paid_amount: Money,
+    pub refunded_amount: Money,
 
     pub created_at: OffsetDateTime,
+    pub updated_at: OffsetDateTime,
     pub expire_at: Option<OffsetDateTime>,
 }
 
+/// 按渠道汇总的订单金额
+#[derive(Debug, FromRow)]
+pub struct ChannelTotals {
+    pub channel: String,
+    pub order_count: i64,
+    pub paid_amount: Money,
+    pub refunded_amount: Money,
+}
+
 #[derive(Debug, FromRow, Clone)]
 pub struct OrderItemDetail {
     pub id: Uuid,
     pub item_id: Uuid,
     pub item_type: String,
-    pub original_price: i64,
-    pub unit_price: i64,
-    pub real_amount: i64,
+    pub original_price: Money,
+    pub unit_price: Money,
+    pub real_amount: Money,
     pub extra_info: Option<Value>,
 }
 
@@ -43,13 +58,13 @@ pub struct OrderDetail {
     pub channel: String,
     pub channel_no: Option<String>,
     pub status: OrderStatus,
-    pub total_items_amount: i64,
-    pub payment_fee: i64,
-    pub discount_amount: i64,
-    pub payable_amount: i64,
-    pub paid_amount: i64,
-    pub refunded_amount: i64,
-    pub refund_fee: i64,
+    pub total_items_amount: Money,
+    pub payment_fee: Money,
+    pub discount_amount: Money,
+    pub payable_amount: Money,
+    pub paid_amount: Money,
+    pub refunded_amount: Money,
+    pub refund_fee: Money,
     pub created_at: OffsetDateTime,
     pub updated_at: OffsetDateTime,
     pub expire_at: Option<OffsetDateTime>,
@@ -59,7 +74,7 @@ pub struct OrderDetail {
 }
 
 #[repr(i16)]
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 #[serde(rename_all = "snake_case")]
 pub enum OrderStatus {
@@ -93,6 +108,71 @@ pub enum OrderStatus {
     Refunded = 50,
 }
 
+impl OrderStatus {
+    /// 与 serde 序列化一致的 snake_case 名称
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Processing => "processing",
+            OrderStatus::Suspended => "suspended",
+            OrderStatus::Fulfilled => "fulfilled",
+            OrderStatus::Completed => "completed",
+            OrderStatus::Canceled => "canceled",
+            OrderStatus::Closed => "closed",
+            OrderStatus::Refunded => "refunded",
+        }
+    }
+
+    /// 订单状态机中是否允许从当前状态流转到 `to`
+    ///
+    /// 已有付款的订单在任何非 Pending 状态下都可能整单退款，因此均可流转至 Refunded
+    pub fn can_transition_to(self, to: OrderStatus) -> bool {
+        use OrderStatus::*;
+
+        matches!(
+            (self, to),
+            (
+                Pending,
+                Processing | Fulfilled | Suspended | Canceled | Closed
+            ) | (
+                Processing,
+                Fulfilled | Suspended | Canceled | Closed | Refunded
+            ) | (Suspended, Processing | Closed | Refunded)
+                | (Fulfilled, Completed | Refunded)
+                | (Canceled | Closed, Pending | Refunded)
+                | (Completed, Refunded)
+        )
+    }
+}
+
+impl std::fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid order status: {0}")]
+pub struct ParseOrderStatusError(String);
+
+impl std::str::FromStr for OrderStatus {
+    type Err = ParseOrderStatusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(OrderStatus::Pending),
+            "processing" => Ok(OrderStatus::Processing),
+            "suspended" => Ok(OrderStatus::Suspended),
+            "fulfilled" => Ok(OrderStatus::Fulfilled),
+            "completed" => Ok(OrderStatus::Completed),
+            "canceled" => Ok(OrderStatus::Canceled),
+            "closed" => Ok(OrderStatus::Closed),
+            "refunded" => Ok(OrderStatus::Refunded),
+            _ => Err(ParseOrderStatusError(s.to_string())),
+        }
+    }
+}
+
 impl sqlx::Type<sqlx::Postgres> for OrderStatus {
     fn type_info() -> sqlx::postgres::PgTypeInfo {
         <i16 as sqlx::Type<sqlx::Postgres>>::type_info()
@@ -103,6 +183,12 @@ impl sqlx::Type<sqlx::Postgres> for OrderStatus {
     }
 }
 
+impl sqlx::postgres::PgHasArrayType for OrderStatus {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        <i16 as sqlx::postgres::PgHasArrayType>::array_type_info()
+    }
+}
+
 impl<'q> sqlx::Encode<'q, sqlx::Postgres> for OrderStatus {
     fn encode_by_ref(
         &self,
@@ -128,7 +214,7 @@ impl<'r> sqlx::Decode<'r, sqlx::Postgres> for OrderStatus {
             40 => Ok(OrderStatus::Canceled),
             45 => Ok(OrderStatus::Closed),
             50 => Ok(OrderStatus::Refunded),
-            _ => Err(format!("Invalid OrderStatus value: {}", val).into()),
+            _ => Err(InvalidEnumValue::new::<Self>(val).into()),
         }
     }
 }
@@ -140,19 +226,60 @@ pub struct CreateOrder {
 
     pub items: Vec<CreateOrderItem>,
 
-    pub payment_fee: Option<i64>,
-    pub discount_amount: Option<i64>,
+    pub payment_fee: Option<Money>,
+    pub discount_amount: Option<Money>,
 
     pub extra_info: Option<serde_json::Value>,
+
+    /// 订单过期时间，过期未付款的订单会被 [`OrderService::cancel_expired_orders`] 取消
+    pub expire_at: Option<OffsetDateTime>,
+}
+
+impl CreateOrder {
+    fn totals(&self) -> Result<OrderTotals, OrderError> {
+        calculate_order_totals(&self.items, self.payment_fee, self.discount_amount)
+            .ok_or(OrderError::AmountOverflow)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderTotals {
+    pub total_items_amount: Money,
+    pub payment_fee: Money,
+    pub discount_amount: Money,
+    pub payable_amount: Money,
+}
+
+/// 计算订单金额，与 [`OrderService::create_order`] 写入的金额一致，可用于下单前预览应付金额
+/// 金额溢出时返回 `None`
+pub fn calculate_order_totals(
+    items: &[CreateOrderItem],
+    payment_fee: Option<Money>,
+    discount_amount: Option<Money>,
+) -> Option<OrderTotals> {
+    let total_items_amount = items
+        .iter()
+        .try_fold(Money::ZERO, |sum, i| sum.checked_add(i.unit_price))?;
+    let payment_fee = payment_fee.unwrap_or_default();
+    let discount_amount = discount_amount.unwrap_or_default();
+
+    Some(OrderTotals {
+        total_items_amount,
+        payment_fee,
+        discount_amount,
+        payable_amount: total_items_amount
+            .checked_add(payment_fee)?
+            .checked_sub(discount_amount)?,
+    })
 }
 
 pub struct CreateOrderItem {
     pub item_type: String,
     pub item_id: Uuid,
 
-    pub original_price: i64,
-    pub unit_price: i64,
-    pub real_amount: i64,
+    pub original_price: Money,
+    pub unit_price: Money,
+    pub real_amount: Money,
 
     pub extra_info: Option<serde_json::Value>,
 }
@@ -162,8 +289,12 @@ pub struct PaymentResult {
     pub order_id: Uuid,
     pub previous_status: OrderStatus,
     pub current_status: OrderStatus,
-    pub paid_amount: i64,
-    pub payable_amount: i64,
+    pub paid_amount: Money,
+    pub payable_amount: Money,
+    /// 本次判定使用的 Fulfilled 阈值金额，见 [`OrderService::fulfill_percent`]
+    pub fulfill_threshold: Money,
+    /// `payment_id` 已入账过，本次调用未做任何修改
+    pub duplicate: bool,
 }
 
 impl PaymentResult {
@@ -181,8 +312,10 @@ pub struct RefundResult {
     pub order_id: Uuid,
     pub previous_status: OrderStatus,
     pub current_status: OrderStatus,
-    pub refunded_amount: i64,
-    pub paid_amount: i64,
+    pub refunded_amount: Money,
+    pub paid_amount: Money,
+    /// 累计退款手续费
+    pub refund_fee: Money,
 }
 
 impl RefundResult {
@@ -191,14 +324,98 @@ impl RefundResult {
     }
 }
 
+/// 订单状态变更记录
+#[derive(Debug, FromRow)]
+pub struct OrderStatusChange {
+    pub order_id: Uuid,
+    pub from_status: OrderStatus,
+    pub to_status: OrderStatus,
+    pub note: Option<String>,
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OrderError {
+    /// 订单当前状态不允许执行该操作
+    #[error("cannot {action} an order in {from:?} status")]
+    InvalidTransition {
+        from: OrderStatus,
+        action: &'static str,
+    },
+
+    /// 退款后的累计退款金额将超过已付金额
+    #[error("cannot refund {requested}: paid {paid}, already refunded {already_refunded}")]
+    OverRefund {
+        paid: Money,
+        already_refunded: Money,
+        requested: Money,
+    },
+
+    /// 金额必须为正数
+    #[error("amount must be positive, got {0}")]
+    InvalidAmount(Money),
+
+    /// 金额计算溢出
+    #[error("amount overflow")]
+    AmountOverflow,
+
+    /// 手续费不能为负数
+    #[error("fee must not be negative, got {0}")]
+    NegativeFee(Money),
+
+    /// 优惠金额超过商品金额与手续费之和，应付金额将为负数
+    #[error("payable amount must not be negative, got {0}")]
+    NegativePayable(Money),
+
+    /// 支付单已计入其他订单
+    #[error("payment_id {0} is already recorded for another order")]
+    PaymentIdTaken(Uuid),
+
+    /// 同一渠道下的渠道订单号已被其他订单使用
+    #[error("channel_no {0} is already used by another order")]
+    ChannelNoTaken(String),
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("fulfill percent must be within 1..=100, got {0}")]
+pub struct InvalidFulfillPercent(pub u8);
+
 #[derive(Debug, Clone)]
 pub struct OrderService {
     pool: sqlx::PgPool,
+    fulfill_percent: i64,
 }
 
 impl OrderService {
     pub fn new(pool: sqlx::PgPool) -> Self {
-        Self { pool }
+        Self {
+            pool,
+            fulfill_percent: 100,
+        }
+    }
+
+    /// 创建服务前检查 jidan 的表是否存在，用于在启动时发现未执行迁移的问题
+    pub async fn connect_and_verify(pool: sqlx::PgPool) -> Result<Self, sqlx::Error> {
+        sqlx::query("SELECT 1 FROM jidan.orders LIMIT 0")
+            .execute(&pool)
+            .await?;
+
+        Ok(Self::new(pool))
+    }
+
+    /// 已付金额达到应付金额的百分之几时将订单流转为 Fulfilled，默认为 100
+    /// 如设置为 30 表示付定金后即可交付，尾款仍可通过 [`OrderService::add_payment`] 记录
+    ///
+    /// errors: `percent` 不在 1..=100 内时返回 [`InvalidFulfillPercent`]
+    pub fn fulfill_percent(mut self, percent: u8) -> Result<Self, InvalidFulfillPercent> {
+        if !(1..=100).contains(&percent) {
+            return Err(InvalidFulfillPercent(percent));
+        }
+        self.fulfill_percent = percent as i64;
+        Ok(self)
     }
 }
 
@@ -208,12 +425,8 @@ impl OrderService {
         order_id: Uuid,
         info: CreateOrder,
         conn: &mut PgConnection,
-    ) -> Result<(), sqlx::Error> {
-        let total_items_amount: i64 = info.items.iter().map(|i| i.unit_price).sum();
-        let payment_fee: i64 = info.payment_fee.unwrap_or(0);
-        let discount_amount: i64 = info.discount_amount.unwrap_or(0);
-
-        let payable_amount: i64 = total_items_amount + payment_fee - discount_amount;
+    ) -> Result<(), OrderError> {
+        let totals = info.totals()?;
 
         sqlx::query(
             r#"
@@ -221,13 +434,13 @@ impl OrderService {
                 id, user_id, channel, channel_no, status,
                 total_items_amount, payment_fee, discount_amount,
                 payable_amount,
-                extra_info
+                extra_info, expire_at
             )
             VALUES (
                 $1, $2, $3, $4, $5,
                 $6, $7, $8,
                 $9,
-                $10
+                $10, $11
             )
             "#,
         )
@@ -236,19 +449,20 @@ impl OrderService {
         .bind(info.channel)
         .bind(info.channel_no)
         .bind(OrderStatus::Pending)
-        .bind(total_items_amount)
-        .bind(payment_fee)
-        .bind(discount_amount)
-        .bind(payable_amount)
+        .bind(totals.total_items_amount)
+        .bind(totals.payment_fee)
+        .bind(totals.discount_amount)
+        .bind(totals.payable_amount)
         .bind(info.extra_info)
+        .bind(info.expire_at)
         .execute(&mut *conn)
         .await?;
 
         let item_type: Vec<String> = info.items.iter().map(|i| i.item_type.clone()).collect();
         let item_id: Vec<Uuid> = info.items.iter().map(|i| i.item_id).collect();
-        let original_price: Vec<i64> = info.items.iter().map(|i| i.original_price).collect();
-        let unit_price: Vec<i64> = info.items.iter().map(|i| i.unit_price).collect();
-        let real_amount: Vec<i64> = info.items.iter().map(|i| i.real_amount).collect();
+        let original_price: Vec<Money> = info.items.iter().map(|i| i.original_price).collect();
+        let unit_price: Vec<Money> = info.items.iter().map(|i| i.unit_price).collect();
+        let real_amount: Vec<Money> = info.items.iter().map(|i| i.real_amount).collect();
         let extra_info: Vec<Option<Value>> =
             info.items.iter().map(|i| i.extra_info.clone()).collect();
 
@@ -282,20 +496,163 @@ impl OrderService {
         Ok(())
     }
 
-    pub async fn create_order(&self, info: CreateOrder, conn: &mut PgConnection) -> Uuid {
+    pub async fn create_order(
+        &self,
+        info: CreateOrder,
+        conn: &mut PgConnection,
+    ) -> Result<Uuid, OrderError> {
         let id = Uuid::now_v7();
 
-        self.create_order_with_id(id, info, conn).await;
+        self.create_order_with_id(id, info, conn).await?;
+
+        Ok(id)
+    }
+
+    /// 批量创建订单，订单与物品各用一条 UNNEST 语句插入
+    /// 订单 id 由调用方提供，重复导入时会因主键冲突而失败，可据此保证幂等
+    /// 两条语句需在同一事务中才能保证原子性，调用方应传入事务连接
+    pub async fn create_orders(
+        &self,
+        orders: Vec<(Uuid, CreateOrder)>,
+        conn: &mut PgConnection,
+    ) -> Result<(), OrderError> {
+        if orders.is_empty() {
+            return Ok(());
+        }
+
+        let mut id = Vec::with_capacity(orders.len());
+        let mut user_id = Vec::with_capacity(orders.len());
+        let mut channel = Vec::with_capacity(orders.len());
+        let mut channel_no: Vec<Option<String>> = Vec::with_capacity(orders.len());
+        let mut total_items_amount = Vec::with_capacity(orders.len());
+        let mut payment_fee = Vec::with_capacity(orders.len());
+        let mut discount_amount = Vec::with_capacity(orders.len());
+        let mut payable_amount = Vec::with_capacity(orders.len());
+        let mut extra_info: Vec<Option<Value>> = Vec::with_capacity(orders.len());
+        let mut expire_at: Vec<Option<OffsetDateTime>> = Vec::with_capacity(orders.len());
+
+        let mut item_order_id = Vec::new();
+        let mut item_id = Vec::new();
+        let mut item_type = Vec::new();
+        let mut original_price = Vec::new();
+        let mut unit_price = Vec::new();
+        let mut real_amount = Vec::new();
+        let mut item_extra_info: Vec<Option<Value>> = Vec::new();
+
+        for (order_id, info) in orders {
+            let totals = info.totals()?;
+
+            id.push(order_id);
+            user_id.push(info.user_id);
+            channel.push(info.channel);
+            channel_no.push(info.channel_no);
+            total_items_amount.push(totals.total_items_amount);
+            payment_fee.push(totals.payment_fee);
+            discount_amount.push(totals.discount_amount);
+            payable_amount.push(totals.payable_amount);
+            extra_info.push(info.extra_info);
+            expire_at.push(info.expire_at);
+
+            for item in info.items {
+                item_order_id.push(order_id);
+                item_id.push(item.item_id);
+                item_type.push(item.item_type);
+                original_price.push(item.original_price);
+                unit_price.push(item.unit_price);
+                real_amount.push(item.real_amount);
+                item_extra_info.push(item.extra_info);
+            }
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO jidan.orders (
+                id, user_id, channel, channel_no, status,
+                total_items_amount, payment_fee, discount_amount,
+                payable_amount,
+                extra_info, expire_at
+            )
+            SELECT
+                id, user_id, channel, channel_no, $1,
+                total_items_amount, payment_fee, discount_amount,
+                payable_amount,
+                extra_info, expire_at
+            FROM UNNEST($2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                AS t (
+                    id, user_id, channel, channel_no,
+                    total_items_amount, payment_fee, discount_amount,
+                    payable_amount,
+                    extra_info, expire_at
+                )
+            "#,
+        )
+        .bind(OrderStatus::Pending)
+        .bind(id)
+        .bind(user_id)
+        .bind(channel)
+        .bind(channel_no)
+        .bind(total_items_amount)
+        .bind(payment_fee)
+        .bind(discount_amount)
+        .bind(payable_amount)
+        .bind(extra_info)
+        .bind(expire_at)
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO jidan.order_items (
+                order_id, item_id, item_type,
+                original_price, unit_price, real_amount, extra_info
+            )
+            SELECT *
+            FROM UNNEST($1, $2, $3, $4, $5, $6, $7)
+                AS t (order_id, item_id, item_type, original_price, unit_price, real_amount, extra_info)
+            "#,
+        )
+        .bind(item_order_id)
+        .bind(item_id)
+        .bind(item_type)
+        .bind(original_price)
+        .bind(unit_price)
+        .bind(real_amount)
+        .bind(item_extra_info)
+        .execute(&mut *conn)
+        .await?;
 
-        id
+        Ok(())
     }
 
-    /// 不做任何检查，将order设置为 fulfilled 状态
+    /// 不检查付款金额，直接将订单设置为 Fulfilled 状态，已是 Fulfilled 时不做修改
+    /// errors: 订单当前状态不允许流转为 Fulfilled 时返回 [`OrderError::InvalidTransition`]
     pub async fn fulfill_order(
         &self,
         order_id: Uuid,
         conn: &mut PgConnection,
-    ) -> Result<(), sqlx::Error> {
+    ) -> Result<(), OrderError> {
+        let current_status: OrderStatus = sqlx::query_scalar(
+            r#"
+            SELECT status as "status: OrderStatus"
+            FROM jidan.orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if current_status == OrderStatus::Fulfilled {
+            return Ok(());
+        }
+        if !current_status.can_transition_to(OrderStatus::Fulfilled) {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "fulfill_order",
+            });
+        }
+
         sqlx::query(
             r#"
             UPDATE jidan.orders
@@ -305,48 +662,127 @@ impl OrderService {
         )
         .bind(OrderStatus::Fulfilled)
         .bind(order_id)
-        .execute(conn)
+        .execute(&mut *conn)
+        .await?;
+
+        record_status_change(
+            order_id,
+            current_status,
+            OrderStatus::Fulfilled,
+            "fulfill_order",
+            &mut *conn,
+        )
         .await?;
 
         Ok(())
     }
 
     /// 记录支付金额，并将订单转换为Processing状态
-    /// 如果支付金额达到或超过应付金额，自动转换为Fulfilled状态
-    /// panics: 订单需在Pending或Processing状态
+    /// 如果支付金额达到 Fulfilled 阈值（默认为应付金额），自动转换为Fulfilled状态
+    /// Fulfilled 但尚未付清的订单可以继续记录支付，状态保持不变
+    /// 每笔支付都记入 jidan.order_payments，传入的 `payment_id` 已入账过时不做任何修改，
+    /// 返回的 [`PaymentResult::duplicate`] 为 true，用于支付回调重复投递的场景
+    /// errors:
+    /// - 订单不在 Pending 或 Processing 状态，且不是未付清的 Fulfilled 订单时返回 [`OrderError::InvalidTransition`]
+    /// - `payment_id` 已计入其他订单时返回 [`OrderError::PaymentIdTaken`]
     pub async fn add_payment(
         &self,
         order_id: Uuid,
-        payment_amount: i64,
+        payment_amount: Money,
+        payment_id: Option<Uuid>,
         conn: &mut PgConnection,
-    ) -> PaymentResult {
-        let (current_status, current_paid_amount, payable_amount): (OrderStatus, i64, i64) =
+    ) -> Result<PaymentResult, OrderError> {
+        // 锁住订单行，同一订单的并发入账（包括重复的 payment_id）依次执行
+        let (current_status, current_paid_amount, payable_amount): (OrderStatus, Money, Money) =
             sqlx::query_as(
                 r#"
                 SELECT status as "status: OrderStatus", paid_amount, payable_amount
                 FROM jidan.orders
                 WHERE id = $1
+                FOR UPDATE
                 "#,
             )
             .bind(order_id)
             .fetch_one(&mut *conn)
-            .await
-            .unwrap();
+            .await?;
+
+        let fulfill_threshold = fulfill_threshold(payable_amount, self.fulfill_percent)
+            .ok_or(OrderError::AmountOverflow)?;
+
+        if let Some(payment_id) = payment_id {
+            let recorded_order = sqlx::query_scalar::<_, Uuid>(
+                "SELECT order_id FROM jidan.order_payments WHERE payment_id = $1",
+            )
+            .bind(payment_id)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+            if recorded_order.is_some_and(|id| id != order_id) {
+                return Err(OrderError::PaymentIdTaken(payment_id));
+            }
+            if recorded_order.is_some() {
+                return Ok(PaymentResult {
+                    order_id,
+                    previous_status: current_status,
+                    current_status,
+                    paid_amount: current_paid_amount,
+                    payable_amount,
+                    fulfill_threshold,
+                    duplicate: true,
+                });
+            }
+        }
 
         match current_status {
             OrderStatus::Pending | OrderStatus::Processing => {}
-            _ => panic!("Order must be in pending or processing status to add payment"),
+            OrderStatus::Fulfilled if current_paid_amount < payable_amount => {}
+            from => {
+                return Err(OrderError::InvalidTransition {
+                    from,
+                    action: "add_payment",
+                });
+            }
         }
 
-        let new_paid_amount = current_paid_amount + payment_amount;
+        let new_paid_amount = current_paid_amount
+            .checked_add(payment_amount)
+            .ok_or(OrderError::AmountOverflow)?;
 
-        // 自动判定状态：如果已付金额 >= 应付金额，则流转为 Fulfilled，否则为 Processing
-        let new_status = if new_paid_amount >= payable_amount {
+        // 自动判定状态：如果已付金额 >= 阈值，则流转为 Fulfilled，否则为 Processing
+        let new_status = if new_paid_amount >= fulfill_threshold {
             OrderStatus::Fulfilled
         } else {
             OrderStatus::Processing
         };
 
+        if new_status != current_status && !current_status.can_transition_to(new_status) {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "add_payment",
+            });
+        }
+
+        // 不同订单并发使用同一 payment_id 时由唯一约束拦截
+        sqlx::query(
+            "INSERT INTO jidan.order_payments (order_id, payment_id, amount) VALUES ($1, $2, $3)",
+        )
+        .bind(order_id)
+        .bind(payment_id)
+        .bind(payment_amount)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| {
+            match (
+                e.as_database_error().and_then(|e| e.constraint()),
+                payment_id,
+            ) {
+                (Some("order_payments_payment_id_key"), Some(payment_id)) => {
+                    OrderError::PaymentIdTaken(payment_id)
+                }
+                _ => e.into(),
+            }
+        })?;
+
         sqlx::query(
             r#"
             UPDATE jidan.orders
@@ -358,40 +794,82 @@ impl OrderService {
         .bind(new_paid_amount)
         .bind(order_id)
         .execute(&mut *conn)
-        .await
-        .unwrap();
+        .await?;
+
+        if new_status != current_status {
+            record_status_change(
+                order_id,
+                current_status,
+                new_status,
+                "add_payment",
+                &mut *conn,
+            )
+            .await?;
+        }
 
-        PaymentResult {
+        Ok(PaymentResult {
             order_id,
             previous_status: current_status,
             current_status: new_status,
             paid_amount: new_paid_amount,
             payable_amount,
-        }
+            fulfill_threshold,
+            duplicate: false,
+        })
     }
 
     /// 记录退款金额，并根据退款情况更新订单状态
     /// 如果已退款金额 >= 已付金额，状态将更新为 Refunded
+    /// `fee` 为渠道收取的退款手续费，累加到 refund_fee，不计入退款金额
+    /// errors:
+    /// - 退款金额非正数时返回 [`OrderError::InvalidAmount`]
+    /// - 手续费为负数时返回 [`OrderError::NegativeFee`]
+    /// - 累计退款金额超过已付金额时返回 [`OrderError::OverRefund`]
+    /// - 订单当前状态不允许流转为 Refunded 时返回 [`OrderError::InvalidTransition`]
     pub async fn add_refund(
         &self,
         order_id: Uuid,
-        refund_amount: i64,
+        refund_amount: Money,
+        fee: Option<Money>,
         conn: &mut PgConnection,
-    ) -> RefundResult {
-        let (current_status, paid_amount, current_refunded_amount): (OrderStatus, i64, i64) =
-            sqlx::query_as(
-                r#"
-                SELECT status as "status: OrderStatus", paid_amount, refunded_amount
-                FROM jidan.orders
-                WHERE id = $1
-                "#,
-            )
-            .bind(order_id)
-            .fetch_one(&mut *conn)
-            .await
-            .unwrap();
+    ) -> Result<RefundResult, OrderError> {
+        let (current_status, paid_amount, current_refunded_amount, current_refund_fee): (
+            OrderStatus,
+            Money,
+            Money,
+            Money,
+        ) = sqlx::query_as(
+            r#"
+            SELECT status as "status: OrderStatus", paid_amount, refunded_amount, refund_fee
+            FROM jidan.orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if !refund_amount.is_positive() {
+            return Err(OrderError::InvalidAmount(refund_amount));
+        }
+        if let Some(fee) = fee.filter(|fee| fee.cents() < 0) {
+            return Err(OrderError::NegativeFee(fee));
+        }
+        let new_refunded_amount = current_refunded_amount
+            .checked_add(refund_amount)
+            .ok_or(OrderError::AmountOverflow)?;
+        if new_refunded_amount > paid_amount {
+            return Err(OrderError::OverRefund {
+                paid: paid_amount,
+                already_refunded: current_refunded_amount,
+                requested: refund_amount,
+            });
+        }
 
-        let new_refunded_amount = current_refunded_amount + refund_amount;
+        let new_refund_fee = current_refund_fee
+            .checked_add(fee.unwrap_or_default())
+            .ok_or(OrderError::AmountOverflow)?;
 
         // 自动判定状态：如果已退款金额 >= 已付金额，则流转为 Refunded
         // 注意：这只是一个基础策略，具体的业务可能需要更复杂的判断
@@ -401,59 +879,116 @@ impl OrderService {
             current_status
         };
 
+        if new_status != current_status && !current_status.can_transition_to(new_status) {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "add_refund",
+            });
+        }
+
         sqlx::query(
             r#"
             UPDATE jidan.orders
-            SET status = $1, refunded_amount = $2, updated_at = now()
-            WHERE id = $3
+            SET status = $1, refunded_amount = $2, refund_fee = $3, updated_at = now()
+            WHERE id = $4
             "#,
         )
         .bind(new_status)
         .bind(new_refunded_amount)
+        .bind(new_refund_fee)
         .bind(order_id)
         .execute(&mut *conn)
-        .await
-        .unwrap();
+        .await?;
+
+        if new_status != current_status {
+            record_status_change(
+                order_id,
+                current_status,
+                new_status,
+                "add_refund",
+                &mut *conn,
+            )
+            .await?;
+        }
 
-        RefundResult {
+        Ok(RefundResult {
             order_id,
             previous_status: current_status,
             current_status: new_status,
             refunded_amount: new_refunded_amount,
             paid_amount,
-        }
+            refund_fee: new_refund_fee,
+        })
     }
 
     /// 扫描并取消所有已过期的订单 (expire_at < now)
     /// 仅针对 Pending 状态的订单生效
     /// 返回修改的订单数
-    pub async fn cancel_expired_orders(&self, conn: &mut PgConnection) -> u64 {
+    pub async fn cancel_expired_orders(&self, conn: &mut PgConnection) -> Result<u64, sqlx::Error> {
         let result = sqlx::query(
             r#"
-            UPDATE jidan.orders
-            SET status = $1, updated_at = now()
-            WHERE
-                status = $2
-                AND expire_at IS NOT NULL
-                AND expire_at < now()
+            WITH updated AS (
+                UPDATE jidan.orders
+                SET status = $1, updated_at = now()
+                WHERE
+                    status = $2
+                    AND expire_at IS NOT NULL
+                    AND expire_at < now()
+                RETURNING id
+            )
+            INSERT INTO jidan.order_status_history (order_id, from_status, to_status, note)
+            SELECT id, $2, $1, 'cancel_expired_orders'
+            FROM updated
             "#,
         )
         .bind(OrderStatus::Canceled)
         .bind(OrderStatus::Pending)
         .execute(conn)
-        .await
-        .unwrap();
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// 扫描并完成所有处于 Fulfilled 状态超过 `older_than` 的订单 (updated_at < now - older_than)
+    /// 付定金后交付、尚有尾款未付的订单不会被完成，见 [`OrderService::fulfill_percent`]
+    /// 返回修改的订单数
+    pub async fn complete_stale_fulfilled(
+        &self,
+        older_than: time::Duration,
+        conn: &mut PgConnection,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            WITH updated AS (
+                UPDATE jidan.orders
+                SET status = $1, updated_at = now()
+                WHERE
+                    status = $2
+                    AND paid_amount >= payable_amount
+                    AND updated_at < now() - $3
+                RETURNING id
+            )
+            INSERT INTO jidan.order_status_history (order_id, from_status, to_status, note)
+            SELECT id, $2, $1, 'complete_stale_fulfilled'
+            FROM updated
+            "#,
+        )
+        .bind(OrderStatus::Completed)
+        .bind(OrderStatus::Fulfilled)
+        .bind(older_than)
+        .execute(conn)
+        .await?;
 
-        result.rows_affected()
+        Ok(result.rows_affected())
     }
 
     /// 将 Fulfilled 状态的订单手动标记为 Completed
-    /// panics: 订单需在 Fulfilled 状态
+    /// errors: 订单不在 Fulfilled 状态时返回 [`OrderError::InvalidTransition`]
     pub async fn complete_order(
         &self,
         order_id: Uuid,
         conn: &mut PgConnection,
-    ) -> Result<(), sqlx::Error> {
+    ) -> Result<(), OrderError> {
         let current_status: OrderStatus = sqlx::query_scalar(
             r#"
             SELECT status as "status: OrderStatus"
@@ -465,9 +1000,11 @@ impl OrderService {
         .fetch_one(&mut *conn)
         .await?;
 
-        match current_status {
-            OrderStatus::Fulfilled => {}
-            _ => panic!("Order must be in fulfilled status to be completed"),
+        if !current_status.can_transition_to(OrderStatus::Completed) {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "complete_order",
+            });
         }
 
         sqlx::query(
@@ -482,61 +1019,437 @@ impl OrderService {
         .execute(&mut *conn)
         .await?;
 
+        record_status_change(
+            order_id,
+            current_status,
+            OrderStatus::Completed,
+            "complete_order",
+            &mut *conn,
+        )
+        .await?;
+
         Ok(())
     }
 
-    pub async fn close_order(
+    /// 将 Pending/Processing 状态的订单挂起，等待人工介入
+    /// `reason_patch` 会合并进订单的 extra_info，用于记录挂起原因
+    /// errors: 订单当前状态不允许挂起时返回 [`OrderError::InvalidTransition`]
+    pub async fn suspend_order(
         &self,
         order_id: Uuid,
-        extra_info_patch: Option<serde_json::Value>,
+        reason_patch: Option<serde_json::Value>,
         conn: &mut PgConnection,
-    ) -> Result<(), sqlx::Error> {
-        if let Some(patch) = extra_info_patch {
-            sqlx::query(
-                r#"
-                UPDATE jidan.orders
-                SET status = $1, updated_at = now(), extra_info = COALESCE(extra_info, '{}'::jsonb) || $2
-                WHERE id = $3
-                "#,
-            )
-            .bind(OrderStatus::Closed)
-            .bind(patch)
-            .bind(order_id)
-            .execute(&mut *conn)
-            .await?;
-        } else {
-            sqlx::query(
-                r#"
-                UPDATE jidan.orders
-                SET status = $1, updated_at = now()
-                WHERE id = $2
-                "#,
-            )
-            .bind(OrderStatus::Closed)
-            .bind(order_id)
-            .execute(&mut *conn)
-            .await?;
-        }
+    ) -> Result<(), OrderError> {
+        let current_status: OrderStatus = sqlx::query_scalar(
+            r#"
+            SELECT status as "status: OrderStatus"
+            FROM jidan.orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
 
-        Ok(())
-    }
+        if !current_status.can_transition_to(OrderStatus::Suspended) {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "suspend_order",
+            });
+        }
 
-    pub async fn update_order_extra_info(
-        &self,
-        order_id: Uuid,
-        extra_info: serde_json::Value,
-        conn: &mut PgConnection,
-    ) -> Result<(), sqlx::Error> {
         sqlx::query(
             r#"
             UPDATE jidan.orders
-            SET updated_at = now(), extra_info = COALESCE(extra_info, '{}'::jsonb) || $1
-            WHERE id = $2
+            SET status = $1, updated_at = now(), extra_info = COALESCE(extra_info, '{}'::jsonb) || COALESCE($2, '{}'::jsonb)
+            WHERE id = $3
             "#,
         )
-        .bind(extra_info)
+        .bind(OrderStatus::Suspended)
+        .bind(reason_patch)
         .bind(order_id)
-        .execute(conn)
+        .execute(&mut *conn)
+        .await?;
+
+        record_status_change(
+            order_id,
+            current_status,
+            OrderStatus::Suspended,
+            "suspend_order",
+            &mut *conn,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// 将 Suspended 状态的订单恢复为 Processing
+    /// errors: 订单不在 Suspended 状态时返回 [`OrderError::InvalidTransition`]
+    pub async fn resume_order(
+        &self,
+        order_id: Uuid,
+        conn: &mut PgConnection,
+    ) -> Result<(), OrderError> {
+        let current_status: OrderStatus = sqlx::query_scalar(
+            r#"
+            SELECT status as "status: OrderStatus"
+            FROM jidan.orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if current_status != OrderStatus::Suspended {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "resume_order",
+            });
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE jidan.orders
+            SET status = $1, updated_at = now()
+            WHERE id = $2
+            "#,
+        )
+        .bind(OrderStatus::Processing)
+        .bind(order_id)
+        .execute(&mut *conn)
+        .await?;
+
+        record_status_change(
+            order_id,
+            current_status,
+            OrderStatus::Processing,
+            "resume_order",
+            &mut *conn,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// 用户主动取消订单，仅 Pending/Processing 状态的订单可以取消
+    /// errors: 订单当前状态不允许取消时返回 [`OrderError::InvalidTransition`]
+    pub async fn cancel_order(
+        &self,
+        order_id: Uuid,
+        conn: &mut PgConnection,
+    ) -> Result<(), OrderError> {
+        let current_status: OrderStatus = sqlx::query_scalar(
+            r#"
+            SELECT status as "status: OrderStatus"
+            FROM jidan.orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if !current_status.can_transition_to(OrderStatus::Canceled) {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "cancel_order",
+            });
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE jidan.orders
+            SET status = $1, updated_at = now()
+            WHERE id = $2
+            "#,
+        )
+        .bind(OrderStatus::Canceled)
+        .bind(order_id)
+        .execute(&mut *conn)
+        .await?;
+
+        record_status_change(
+            order_id,
+            current_status,
+            OrderStatus::Canceled,
+            "cancel_order",
+            &mut *conn,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// 关闭订单（如审核不通过），仅 Pending/Processing/Suspended 状态的订单可以关闭
+    /// `extra_info_patch` 会合并进订单的 extra_info
+    /// errors: 订单当前状态不允许关闭时返回 [`OrderError::InvalidTransition`]
+    pub async fn close_order(
+        &self,
+        order_id: Uuid,
+        extra_info_patch: Option<serde_json::Value>,
+        conn: &mut PgConnection,
+    ) -> Result<(), OrderError> {
+        let current_status: OrderStatus = sqlx::query_scalar(
+            r#"
+            SELECT status as "status: OrderStatus"
+            FROM jidan.orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if !current_status.can_transition_to(OrderStatus::Closed) {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "close_order",
+            });
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE jidan.orders
+            SET status = $1, updated_at = now(), extra_info = COALESCE(extra_info, '{}'::jsonb) || COALESCE($2, '{}'::jsonb)
+            WHERE id = $3
+            "#,
+        )
+        .bind(OrderStatus::Closed)
+        .bind(extra_info_patch)
+        .bind(order_id)
+        .execute(&mut *conn)
+        .await?;
+
+        record_status_change(
+            order_id,
+            current_status,
+            OrderStatus::Closed,
+            "close_order",
+            &mut *conn,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// 将误关闭/取消的订单恢复为 Pending
+    /// 不会修改 expire_at，若订单已过期需另行处理，否则会被下一次过期扫描再次取消
+    /// errors: 订单不在 Closed 或 Canceled 状态时返回 [`OrderError::InvalidTransition`]
+    pub async fn reopen_order(
+        &self,
+        order_id: Uuid,
+        conn: &mut PgConnection,
+    ) -> Result<(), OrderError> {
+        let current_status: OrderStatus = sqlx::query_scalar(
+            r#"
+            SELECT status as "status: OrderStatus"
+            FROM jidan.orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if !current_status.can_transition_to(OrderStatus::Pending) {
+            return Err(OrderError::InvalidTransition {
+                from: current_status,
+                action: "reopen_order",
+            });
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE jidan.orders
+            SET status = $1, updated_at = now()
+            WHERE id = $2
+            "#,
+        )
+        .bind(OrderStatus::Pending)
+        .bind(order_id)
+        .execute(&mut *conn)
+        .await?;
+
+        record_status_change(
+            order_id,
+            current_status,
+            OrderStatus::Pending,
+            "reopen_order",
+            &mut *conn,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// 按时间顺序返回订单的状态变更记录
+    pub async fn get_order_history(
+        &self,
+        order_id: Uuid,
+        conn: &mut PgConnection,
+    ) -> Result<Vec<OrderStatusChange>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT order_id, from_status, to_status, note, created_at
+            FROM jidan.order_status_history
+            WHERE order_id = $1
+            ORDER BY created_at, id
+            "#,
+        )
+        .bind(order_id)
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// 修改订单的手续费/优惠金额，并重新计算应付金额
+    /// 传入 `None` 的字段保持不变，返回重新计算后的 payable_amount
+    /// Processing 订单的已付金额达到新的付清阈值时，与 [`OrderService::add_payment`] 一样流转为 Fulfilled
+    /// errors:
+    /// - 订单不在 Pending 或 Processing 状态时返回 [`OrderError::InvalidTransition`]
+    /// - 重新计算的应付金额为负数时返回 [`OrderError::NegativePayable`]
+    pub async fn update_order_pricing(
+        &self,
+        order_id: Uuid,
+        payment_fee: Option<Money>,
+        discount_amount: Option<Money>,
+        conn: &mut PgConnection,
+    ) -> Result<Money, OrderError> {
+        let (current_status, total_items_amount, current_fee, current_discount, paid_amount): (
+            OrderStatus,
+            Money,
+            Money,
+            Money,
+            Money,
+        ) = sqlx::query_as(
+            r#"
+            SELECT status as "status: OrderStatus", total_items_amount, payment_fee, discount_amount, paid_amount
+            FROM jidan.orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        match current_status {
+            OrderStatus::Pending | OrderStatus::Processing => {}
+            from => {
+                return Err(OrderError::InvalidTransition {
+                    from,
+                    action: "update_order_pricing",
+                });
+            }
+        }
+
+        let payment_fee = payment_fee.unwrap_or(current_fee);
+        let discount_amount = discount_amount.unwrap_or(current_discount);
+
+        let payable_amount = total_items_amount
+            .checked_add(payment_fee)
+            .and_then(|amount| amount.checked_sub(discount_amount))
+            .ok_or(OrderError::AmountOverflow)?;
+        if payable_amount < Money::ZERO {
+            return Err(OrderError::NegativePayable(payable_amount));
+        }
+
+        let fulfill_threshold = fulfill_threshold(payable_amount, self.fulfill_percent)
+            .ok_or(OrderError::AmountOverflow)?;
+
+        // 降价后部分付款的订单可能已经付清，否则会一直停留在 Processing
+        let new_status =
+            if current_status == OrderStatus::Processing && paid_amount >= fulfill_threshold {
+                OrderStatus::Fulfilled
+            } else {
+                current_status
+            };
+
+        sqlx::query(
+            r#"
+            UPDATE jidan.orders
+            SET status = $1, payment_fee = $2, discount_amount = $3, payable_amount = $4, updated_at = now()
+            WHERE id = $5
+            "#,
+        )
+        .bind(new_status)
+        .bind(payment_fee)
+        .bind(discount_amount)
+        .bind(payable_amount)
+        .bind(order_id)
+        .execute(&mut *conn)
+        .await?;
+
+        if new_status != current_status {
+            record_status_change(
+                order_id,
+                current_status,
+                new_status,
+                "update_order_pricing",
+                &mut *conn,
+            )
+            .await?;
+        }
+
+        Ok(payable_amount)
+    }
+
+    /// 设置渠道订单号，用于下单后才拿到渠道单号（如支付渠道交易号）的场景
+    /// errors:
+    /// - 同一渠道下已有其他订单使用该单号时返回 [`OrderError::ChannelNoTaken`]
+    /// - 订单不存在时与其他方法一致返回 [`sqlx::Error::RowNotFound`]
+    pub async fn set_channel_no(
+        &self,
+        order_id: Uuid,
+        channel_no: &str,
+        conn: &mut PgConnection,
+    ) -> Result<(), OrderError> {
+        let result = sqlx::query(
+            r#"
+            UPDATE jidan.orders
+            SET updated_at = now(), channel_no = $1
+            WHERE id = $2
+            "#,
+        )
+        .bind(channel_no)
+        .bind(order_id)
+        .execute(conn)
+        .await
+        .map_err(
+            |e| match e.as_database_error().and_then(|e| e.constraint()) {
+                Some("orders_channel_no_idx") => OrderError::ChannelNoTaken(channel_no.to_string()),
+                _ => e.into(),
+            },
+        )?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+
+        Ok(())
+    }
+
+    pub async fn update_order_extra_info(
+        &self,
+        order_id: Uuid,
+        extra_info: serde_json::Value,
+        conn: &mut PgConnection,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE jidan.orders
+            SET updated_at = now(), extra_info = COALESCE(extra_info, '{}'::jsonb) || $1
+            WHERE id = $2
+            "#,
+        )
+        .bind(extra_info)
+        .bind(order_id)
+        .execute(conn)
         .await?;
 
         Ok(())
@@ -563,3 +1476,408 @@ impl OrderService {
         Ok(())
     }
 }
+
+/// 应付金额按百分比计算的 Fulfilled 阈值，向上取整，溢出时返回 `None`
+fn fulfill_threshold(payable_amount: Money, percent: i64) -> Option<Money> {
+    let scaled = payable_amount.0.checked_mul(percent)?.checked_add(99)?;
+
+    Some(Money(scaled / 100))
+}
+
+/// 在同一连接中写入一条状态变更记录，调用方负责保证与状态更新处于同一事务
+async fn record_status_change(
+    order_id: Uuid,
+    from: OrderStatus,
+    to: OrderStatus,
+    note: &str,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO jidan.order_status_history (order_id, from_status, to_status, note)
+        VALUES ($1, $2, $3, $4)
+        "#,
+    )
+    .bind(order_id)
+    .bind(from)
+    .bind(to)
+    .bind(note)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 连接 `DATABASE_URL` 指向的数据库，首次调用时执行迁移，以 `cargo test -- --ignored` 运行
+    async fn test_pool() -> sqlx::PgPool {
+        use sea_orm_migration::{MigratorTrait, sea_orm::SqlxPostgresConnector};
+
+        static MIGRATED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is not set");
+        let pool = sqlx::PgPool::connect(&url).await.unwrap();
+
+        MIGRATED
+            .get_or_init(|| async {
+                let db = SqlxPostgresConnector::from_sqlx_postgres_pool(pool.clone());
+                migration::Migrator::up(&db, None).await.unwrap();
+            })
+            .await;
+
+        pool
+    }
+
+    fn test_order(price: i64) -> CreateOrder {
+        CreateOrder {
+            user_id: Uuid::now_v7(),
+            channel: "test".into(),
+            channel_no: None,
+            items: vec![CreateOrderItem {
+                item_type: "course".into(),
+                item_id: Uuid::now_v7(),
+                original_price: Money(price),
+                unit_price: Money(price),
+                real_amount: Money(price),
+                extra_info: None,
+            }],
+            payment_fee: None,
+            discount_amount: None,
+            extra_info: None,
+            expire_at: None,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn batch_and_single_item_queries_agree() {
+        let service = OrderService::new(test_pool().await);
+
+        let mut order = test_order(100);
+        order.items.push(CreateOrderItem {
+            item_type: "book".into(),
+            item_id: Uuid::now_v7(),
+            original_price: Money(50),
+            unit_price: Money(40),
+            real_amount: Money(40),
+            extra_info: Some(serde_json::json!({ "isbn": "978-7" })),
+        });
+        let user_id = order.user_id;
+
+        let mut conn = service.pool.acquire().await.unwrap();
+        let order_id = service.create_order(order, &mut conn).await.unwrap();
+
+        let fields = |mut items: Vec<OrderItemDetail>| {
+            items.sort_by_key(|i| i.id);
+            items
+                .into_iter()
+                .map(|i| {
+                    (
+                        i.id,
+                        i.item_id,
+                        i.item_type,
+                        i.original_price,
+                        i.unit_price,
+                        i.real_amount,
+                        i.extra_info,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let single = service.get_items_of_order(order_id).await.unwrap();
+        let batch = service
+            .query_orders_with_details(OrderQuery::new().user_id(user_id))
+            .await
+            .unwrap();
+
+        assert_eq!(batch.len(), 1);
+        assert_eq!(single.len(), 2);
+        assert_eq!(
+            fields(batch.into_iter().next().unwrap().items),
+            fields(single)
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn update_order_pricing_rejects_negative_payable() {
+        let service = OrderService::new(test_pool().await);
+        let mut tx = service.pool.begin().await.unwrap();
+
+        let order_id = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+
+        let err = service
+            .update_order_pricing(order_id, Some(Money(10)), Some(Money(111)), &mut tx)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OrderError::NegativePayable(Money(-1))));
+
+        let payable = service
+            .update_order_pricing(order_id, Some(Money(10)), Some(Money(110)), &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(payable, Money::ZERO);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn update_order_pricing_fulfills_paid_order() {
+        let service = OrderService::new(test_pool().await);
+        let mut tx = service.pool.begin().await.unwrap();
+
+        let order_id = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+        service
+            .add_payment(order_id, Money(60), None, &mut tx)
+            .await
+            .unwrap();
+
+        let payable = service
+            .update_order_pricing(order_id, None, Some(Money(40)), &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(payable, Money(60));
+
+        let order = service
+            .get_order_detail_by_id_tx(order_id, &mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(order.status, OrderStatus::Fulfilled);
+
+        let history = service.get_order_history(order_id, &mut tx).await.unwrap();
+        assert!(
+            history
+                .iter()
+                .any(|c| c.from_status == OrderStatus::Processing
+                    && c.to_status == OrderStatus::Fulfilled
+                    && c.note.as_deref() == Some("update_order_pricing"))
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn complete_stale_fulfilled_skips_unpaid_deposits() {
+        let service = OrderService::new(test_pool().await)
+            .fulfill_percent(30)
+            .unwrap();
+        let mut tx = service.pool.begin().await.unwrap();
+
+        let deposit = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+        service
+            .add_payment(deposit, Money(30), None, &mut tx)
+            .await
+            .unwrap();
+
+        let paid = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+        service
+            .add_payment(paid, Money(100), None, &mut tx)
+            .await
+            .unwrap();
+
+        // 事务内 now() 不变，取负数使刚流转的订单也满足时间条件
+        service
+            .complete_stale_fulfilled(-time::Duration::HOUR, &mut tx)
+            .await
+            .unwrap();
+
+        for (order_id, status) in [
+            (deposit, OrderStatus::Fulfilled),
+            (paid, OrderStatus::Completed),
+        ] {
+            let order = service
+                .get_order_detail_by_id_tx(order_id, &mut tx)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(order.status, status);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn add_payment_rejects_payment_id_of_another_order() {
+        let service = OrderService::new(test_pool().await);
+        let mut tx = service.pool.begin().await.unwrap();
+
+        let first = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+        let second = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+        let payment_id = Uuid::now_v7();
+
+        service
+            .add_payment(first, Money(40), Some(payment_id), &mut tx)
+            .await
+            .unwrap();
+
+        let repeated = service
+            .add_payment(first, Money(40), Some(payment_id), &mut tx)
+            .await
+            .unwrap();
+        assert!(repeated.duplicate);
+        assert_eq!(repeated.paid_amount, Money(40));
+
+        let err = service
+            .add_payment(second, Money(40), Some(payment_id), &mut tx)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OrderError::PaymentIdTaken(id) if id == payment_id));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn fulfill_order_rejects_terminal_orders() {
+        let service = OrderService::new(test_pool().await);
+        let mut tx = service.pool.begin().await.unwrap();
+
+        let canceled = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+        service.cancel_order(canceled, &mut tx).await.unwrap();
+
+        let refunded = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+        service
+            .add_payment(refunded, Money(100), None, &mut tx)
+            .await
+            .unwrap();
+        service
+            .add_refund(refunded, Money(100), None, &mut tx)
+            .await
+            .unwrap();
+
+        for (order_id, status) in [
+            (canceled, OrderStatus::Canceled),
+            (refunded, OrderStatus::Refunded),
+        ] {
+            let err = service.fulfill_order(order_id, &mut tx).await.unwrap_err();
+            assert!(matches!(
+                err,
+                OrderError::InvalidTransition { from, action: "fulfill_order" } if from == status
+            ));
+        }
+
+        let pending = service
+            .create_order(test_order(100), &mut tx)
+            .await
+            .unwrap();
+        service.fulfill_order(pending, &mut tx).await.unwrap();
+        service.fulfill_order(pending, &mut tx).await.unwrap();
+    }
+
+    #[test]
+    fn order_status_transitions() {
+        use OrderStatus::*;
+
+        assert!(Pending.can_transition_to(Processing));
+        assert!(Pending.can_transition_to(Fulfilled));
+        assert!(Pending.can_transition_to(Suspended));
+        assert!(Processing.can_transition_to(Suspended));
+        assert!(Suspended.can_transition_to(Processing));
+        assert!(Fulfilled.can_transition_to(Completed));
+        assert!(Completed.can_transition_to(Refunded));
+
+        assert!(!Pending.can_transition_to(Refunded));
+        assert!(!Canceled.can_transition_to(Completed));
+        assert!(!Fulfilled.can_transition_to(Canceled));
+        assert!(!Closed.can_transition_to(Fulfilled));
+        assert!(Closed.can_transition_to(Pending));
+        assert!(!Refunded.can_transition_to(Pending));
+        assert!(!Completed.can_transition_to(Pending));
+        assert!(!Fulfilled.can_transition_to(Fulfilled));
+    }
+
+    #[test]
+    fn order_status_str_matches_serde() {
+        use OrderStatus::*;
+
+        for status in [
+            Pending, Processing, Suspended, Fulfilled, Completed, Canceled, Closed, Refunded,
+        ] {
+            let json = serde_json::to_string(&status).unwrap();
+            assert_eq!(json, format!("\"{}\"", status.as_str()));
+            assert_eq!(status.as_str().parse::<OrderStatus>().unwrap(), status);
+            assert_eq!(serde_json::from_str::<OrderStatus>(&json).unwrap(), status);
+        }
+
+        assert!("Processing".parse::<OrderStatus>().is_err());
+    }
+
+    #[test]
+    fn order_totals_apply_fee_and_discount() {
+        let item = |price| CreateOrderItem {
+            item_type: "course".into(),
+            item_id: Uuid::nil(),
+            original_price: Money(price),
+            unit_price: Money(price),
+            real_amount: Money(price),
+            extra_info: None,
+        };
+
+        assert_eq!(
+            calculate_order_totals(&[item(1000), item(500)], Some(Money(10)), Some(Money(200))),
+            Some(OrderTotals {
+                total_items_amount: Money(1500),
+                payment_fee: Money(10),
+                discount_amount: Money(200),
+                payable_amount: Money(1310),
+            })
+        );
+        assert_eq!(
+            calculate_order_totals(&[], None, None).map(|t| t.payable_amount),
+            Some(Money(0))
+        );
+        assert_eq!(
+            calculate_order_totals(&[item(i64::MAX), item(1)], None, None),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn fulfill_percent_rejects_out_of_range() {
+        let service =
+            OrderService::new(sqlx::PgPool::connect_lazy("postgres://localhost/jidan").unwrap());
+
+        assert!(matches!(
+            service.clone().fulfill_percent(0),
+            Err(InvalidFulfillPercent(0))
+        ));
+        assert!(matches!(
+            service.clone().fulfill_percent(101),
+            Err(InvalidFulfillPercent(101))
+        ));
+        assert_eq!(service.fulfill_percent(30).unwrap().fulfill_percent, 30);
+    }
+
+    #[test]
+    fn fulfill_threshold_rounds_up() {
+        assert_eq!(fulfill_threshold(Money(1000), 100), Some(Money(1000)));
+        assert_eq!(fulfill_threshold(Money(1000), 30), Some(Money(300)));
+        assert_eq!(fulfill_threshold(Money(1001), 30), Some(Money(301)));
+        assert_eq!(fulfill_threshold(Money(0), 30), Some(Money(0)));
+        assert_eq!(fulfill_threshold(Money(i64::MAX), 30), None);
+    }
+}
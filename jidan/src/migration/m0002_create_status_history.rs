@@ -0,0 +1,43 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared(
+            r#"
+            CREATE TABLE jidan.order_status_history (
+                id uuid PRIMARY KEY DEFAULT uuidv7(),
+                order_id uuid REFERENCES jidan.orders NOT NULL,
+
+                from_status int2 NOT NULL,
+                to_status int2 NOT NULL,
+                note text,
+
+                created_at timestamptz NOT NULL DEFAULT now()
+            )
+            "#,
+        )
+        .await?;
+
+        db.execute_unprepared(
+            "CREATE INDEX order_status_history_order_id_idx ON jidan.order_status_history (order_id)",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("DROP TABLE jidan.order_status_history")
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        // jsonb_path_ops 只支持 @>，但比默认的 jsonb_ops 更小更快
+        db.execute_unprepared(
+            "CREATE INDEX orders_extra_info_idx ON jidan.orders USING gin (extra_info jsonb_path_ops)",
+        )
+        .await?;
+
+        db.execute_unprepared("CREATE INDEX orders_user_id_idx ON jidan.orders (user_id)")
+            .await?;
+
+        db.execute_unprepared("CREATE INDEX orders_status_idx ON jidan.orders (status)")
+            .await?;
+
+        db.execute_unprepared("CREATE INDEX orders_channel_idx ON jidan.orders (channel)")
+            .await?;
+
+        db.execute_unprepared("CREATE INDEX orders_created_at_idx ON jidan.orders (created_at)")
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared(
+            r#"
+            DROP INDEX
+                jidan.orders_extra_info_idx,
+                jidan.orders_user_id_idx,
+                jidan.orders_status_idx,
+                jidan.orders_channel_idx,
+                jidan.orders_created_at_idx
+            "#,
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        // payment_id 为空表示未关联支付单的入账，唯一约束不限制多个 NULL
+        db.execute_unprepared(
+            r#"
+            CREATE TABLE jidan.order_payments (
+                id uuid PRIMARY KEY DEFAULT uuidv7(),
+                order_id uuid REFERENCES jidan.orders NOT NULL,
+                payment_id uuid UNIQUE,
+                amount int8 NOT NULL,
+
+                created_at timestamptz NOT NULL DEFAULT now()
+            )
+            "#,
+        )
+        .await?;
+
+        db.execute_unprepared(
+            "CREATE INDEX order_payments_order_id_idx ON jidan.order_payments (order_id)",
+        )
+        .await?;
+
+        // 已有的已付金额作为一笔未关联支付单的入账，保持 paid_amount 与流水一致
+        db.execute_unprepared(
+            r#"
+            INSERT INTO jidan.order_payments (order_id, amount, created_at)
+            SELECT id, paid_amount, updated_at
+            FROM jidan.orders
+            WHERE paid_amount <> 0
+            "#,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("DROP TABLE jidan.order_payments")
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        // 已存在重复的渠道订单号时迁移会失败，需先人工处理
+        db.execute_unprepared(
+            r#"
+            CREATE UNIQUE INDEX orders_channel_no_idx ON jidan.orders (channel, channel_no)
+            WHERE channel_no IS NOT NULL
+            "#,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("DROP INDEX jidan.orders_channel_no_idx")
+            .await?;
+
+        Ok(())
+    }
+}
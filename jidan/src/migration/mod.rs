@@ -1,6 +1,10 @@
 use sea_orm_migration::prelude::*;
 
 mod m0001_create_table;
+mod m0002_create_status_history;
+mod m0003_add_order_indexes;
+mod m0004_create_order_payments;
+mod m0005_add_channel_no_unique;
 
 pub struct Migrator;
 
@@ -10,6 +14,12 @@ impl MigratorTrait for Migrator {
     }
 
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m0001_create_table::Migration)]
+        vec![
+            Box::new(m0001_create_table::Migration),
+            Box::new(m0002_create_status_history::Migration),
+            Box::new(m0003_add_order_indexes::Migration),
+            Box::new(m0004_create_order_payments::Migration),
+            Box::new(m0005_add_channel_no_unique::Migration),
+        ]
     }
 }
@@ -0,0 +1,62 @@
+use bokchoy::{PayCallbackResult, PaymentService, Provider, psp::CallbackError};
+use sqlx::PgConnection;
+
+use crate::{Money, OrderError, OrderService, PaymentResult};
+
+#[derive(Debug, Clone)]
+pub struct PaidOrder {
+    pub payment: PayCallbackResult,
+    pub order: PaymentResult,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PayOrderError {
+    #[error(transparent)]
+    Callback(#[from] CallbackError),
+
+    #[error(transparent)]
+    Order(#[from] OrderError),
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+impl OrderService {
+    /// 处理支付回调，并以 `biz_id` 作为订单 id 调用 [`OrderService::add_payment`]
+    /// 以支付单 id 入账，重复投递的回调不会重复计入已付金额
+    /// 两者在同一事务中提交，订单入账失败时支付也不会被标记为成功，支付渠道会重试回调
+    pub async fn handle_pay_callback(
+        &self,
+        payments: &PaymentService,
+        key: Provider,
+        req: http::Request<bytes::Bytes>,
+    ) -> Result<(PaidOrder, http::Response<String>), PayOrderError> {
+        let mut tx = self.pool.begin().await?;
+
+        let (payment, res) = payments.handle_pay_callback(key, req, &mut tx).await?;
+
+        let order = self.add_callback_payment(&payment, &mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok((PaidOrder { payment, order }, res))
+    }
+
+    /// 将 [`PaymentService::handle_pay_callback`] 的结果计入订单，供需要在同一事务中做其他处理的调用方使用
+    ///
+    /// 两者都接受 `&mut PgConnection`，传入同一个事务（`&mut tx`）即可在一次提交中
+    /// 同时写入支付单、支付事件与订单入账，避免支付已记录而订单未入账
+    pub async fn add_callback_payment(
+        &self,
+        payment: &PayCallbackResult,
+        conn: &mut PgConnection,
+    ) -> Result<PaymentResult, OrderError> {
+        self.add_payment(
+            payment.biz_id,
+            Money::from_cents(payment.amount),
+            Some(payment.payment_id),
+            conn,
+        )
+        .await
+    }
+}
@@ -1,37 +1,67 @@
 use std::collections::HashMap;
 
 use serde_json::Value;
-use sqlx::{Postgres, QueryBuilder, Row};
+use sqlx::{FromRow, PgConnection, Postgres, QueryBuilder, Row, postgres::PgRow};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-use crate::{OrderDetail, OrderItemDetail, OrderService, OrderStatus, OrderSummary};
+use crate::{
+    ChannelTotals, Money, OrderDetail, OrderItemDetail, OrderService, OrderStatus, OrderSummary,
+};
 
 #[derive(Debug, Clone)]
 pub struct OrderQuery<'a> {
     pub user_id: Option<Uuid>,
     pub status: Option<OrderStatus>,
+    /// 与 `status` 同时设置时两者都需满足（取交集）
+    pub statuses: Option<&'a [OrderStatus]>,
     pub channel: Option<String>,
+    pub channel_nos: Option<&'a [String]>,
     pub created_after: Option<OffsetDateTime>,
     pub created_before: Option<OffsetDateTime>,
+    pub payable_min: Option<Money>,
+    pub payable_max: Option<Money>,
+    pub paid_min: Option<Money>,
+    pub paid_max: Option<Money>,
     pub has_items: Option<&'a [Uuid]>,
+    pub item_type: Option<String>,
     pub extra_info: Option<&'a Value>,
+    /// 嵌套路径上的值等于给定值，见 [`OrderQuery::extra_info_path`]
+    pub extra_info_path: Option<(&'a [&'a str], &'a Value)>,
     pub item_extra_info: Option<&'a Value>,
+    pub sort: OrderSort,
     pub offset: i64,
     pub limit: Option<i64>,
 }
 
+/// 查询结果的排序方式，创建时间相同时按 id 排序，保证分页稳定
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderSort {
+    #[default]
+    CreatedAtDesc,
+    CreatedAtAsc,
+}
+
 impl Default for OrderQuery<'_> {
     fn default() -> Self {
         Self {
             user_id: None,
             status: None,
+            statuses: None,
             channel: None,
+            channel_nos: None,
             created_after: None,
             created_before: None,
+            payable_min: None,
+            payable_max: None,
+            paid_min: None,
+            paid_max: None,
             has_items: None,
+            item_type: None,
             extra_info: None,
+            extra_info_path: None,
             item_extra_info: None,
+            sort: OrderSort::default(),
             offset: 0,
             limit: Some(20),
         }
@@ -53,11 +83,22 @@ impl<'a> OrderQuery<'a> {
         self
     }
 
+    /// 订单状态属于给定集合之一，如 Pending/Processing/Suspended 表示所有未结束的订单
+    pub fn statuses(mut self, statuses: &'a [OrderStatus]) -> Self {
+        self.statuses = Some(statuses);
+        self
+    }
+
     pub fn channel(mut self, channel: impl Into<String>) -> Self {
         self.channel = Some(channel.into());
         self
     }
 
+    pub fn channel_no_in(mut self, channel_nos: &'a [String]) -> Self {
+        self.channel_nos = Some(channel_nos);
+        self
+    }
+
     pub fn created_after(mut self, t: OffsetDateTime) -> Self {
         self.created_after = Some(t);
         self
@@ -68,23 +109,58 @@ impl<'a> OrderQuery<'a> {
         self
     }
 
+    /// 应付金额在 [min, max] 内，任一端为 `None` 时不限制该端
+    pub fn payable_between(mut self, min: Option<Money>, max: Option<Money>) -> Self {
+        self.payable_min = min;
+        self.payable_max = max;
+        self
+    }
+
+    /// 已付金额在 [min, max] 内，任一端为 `None` 时不限制该端
+    pub fn paid_between(mut self, min: Option<Money>, max: Option<Money>) -> Self {
+        self.paid_min = min;
+        self.paid_max = max;
+        self
+    }
+
     pub fn has_items(mut self, items: &'a [Uuid]) -> Self {
         self.has_items = Some(items);
         self
     }
 
+    /// 订单中含有任一该类型的物品
+    pub fn item_type(mut self, item_type: impl Into<String>) -> Self {
+        self.item_type = Some(item_type.into());
+        self
+    }
+
     pub fn extra_info(mut self, extra_info: &'a Value) -> Self {
         self.extra_info = Some(extra_info);
         self
     }
 
+    /// `extra_info` 中 `path` 处的值等于 `value`，如 `&["coupon", "code"]` 与 `json!("X")`
+    ///
+    /// 路径与值均作为参数绑定，比较使用 jsonb 相等，因此 `json!(1)` 不会匹配 `"1"`
+    pub fn extra_info_path(mut self, path: &'a [&'a str], value: &'a Value) -> Self {
+        self.extra_info_path = Some((path, value));
+        self
+    }
+
     pub fn item_extra_info(mut self, item_extra_info: &'a Value) -> Self {
         self.item_extra_info = Some(item_extra_info);
         self
     }
 
+    pub fn sort(mut self, sort: OrderSort) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    /// `page` 从 1 开始，小于 1 时视为 1，`page_size` 小于 1 时视为 1
     pub fn page(mut self, page: i64, page_size: i64) -> Self {
-        self.offset = (page - 1).max(0) * page_size;
+        let page_size = page_size.max(1);
+        self.offset = (page.max(1) - 1).saturating_mul(page_size);
         self.limit = Some(page_size);
         self
     }
@@ -100,6 +176,14 @@ impl<'a> OrderQuery<'a> {
     }
 }
 
+/// 批量查询物品时附带 order_id，物品本身与 [`OrderService::get_items_of_order`] 共用同一份 FromRow
+#[derive(FromRow)]
+struct OrderItemRow {
+    order_id: Uuid,
+    #[sqlx(flatten)]
+    item: OrderItemDetail,
+}
+
 fn apply_filters<'a>(builder: &mut QueryBuilder<'a, Postgres>, query: &'a OrderQuery) {
     if let Some(uid) = query.user_id {
         builder.push(" AND user_id = ");
@@ -109,10 +193,20 @@ fn apply_filters<'a>(builder: &mut QueryBuilder<'a, Postgres>, query: &'a OrderQ
         builder.push(" AND status = ");
         builder.push_bind(status);
     }
+    if let Some(statuses) = query.statuses {
+        builder.push(" AND status = ANY(");
+        builder.push_bind(statuses);
+        builder.push(")");
+    }
     if let Some(channel) = &query.channel {
         builder.push(" AND channel = ");
         builder.push_bind(channel);
     }
+    if let Some(channel_nos) = query.channel_nos {
+        builder.push(" AND channel_no = ANY(");
+        builder.push_bind(channel_nos);
+        builder.push(")");
+    }
     if let Some(after) = query.created_after {
         builder.push(" AND created_at >= ");
         builder.push_bind(after);
@@ -121,6 +215,22 @@ fn apply_filters<'a>(builder: &mut QueryBuilder<'a, Postgres>, query: &'a OrderQ
         builder.push(" AND created_at < ");
         builder.push_bind(before);
     }
+    if let Some(min) = query.payable_min {
+        builder.push(" AND payable_amount >= ");
+        builder.push_bind(min);
+    }
+    if let Some(max) = query.payable_max {
+        builder.push(" AND payable_amount <= ");
+        builder.push_bind(max);
+    }
+    if let Some(min) = query.paid_min {
+        builder.push(" AND paid_amount >= ");
+        builder.push_bind(min);
+    }
+    if let Some(max) = query.paid_max {
+        builder.push(" AND paid_amount <= ");
+        builder.push_bind(max);
+    }
     if let Some(items) = &query.has_items
         && !items.is_empty()
     {
@@ -128,10 +238,21 @@ fn apply_filters<'a>(builder: &mut QueryBuilder<'a, Postgres>, query: &'a OrderQ
         builder.push_bind(items);
         builder.push("))");
     }
+    if let Some(item_type) = &query.item_type {
+        builder.push(" AND id IN (SELECT order_id FROM jidan.order_items WHERE item_type = ");
+        builder.push_bind(item_type);
+        builder.push(")");
+    }
     if let Some(info) = query.extra_info {
         builder.push(" AND extra_info @> ");
         builder.push_bind(info);
     }
+    if let Some((path, value)) = query.extra_info_path {
+        builder.push(" AND extra_info #> ");
+        builder.push_bind(path);
+        builder.push(" = ");
+        builder.push_bind(value);
+    }
     if let Some(item_info) = query.item_extra_info {
         builder.push(" AND id IN (SELECT order_id FROM jidan.order_items WHERE extra_info @> ");
         builder.push_bind(item_info);
@@ -139,17 +260,58 @@ fn apply_filters<'a>(builder: &mut QueryBuilder<'a, Postgres>, query: &'a OrderQ
     }
 }
 
+/// 排序与分页，仅用于数据查询，计数查询不应调用
+fn apply_pagination<'a>(builder: &mut QueryBuilder<'a, Postgres>, query: &'a OrderQuery) {
+    builder.push(match query.sort {
+        OrderSort::CreatedAtDesc => " ORDER BY created_at DESC, id DESC",
+        OrderSort::CreatedAtAsc => " ORDER BY created_at ASC, id ASC",
+    });
+
+    // Postgres 不接受负数的 LIMIT，直接设置字段时也不应产生运行时错误
+    if let Some(limit) = query.limit {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit.max(0));
+    }
+    if query.offset > 0 {
+        builder.push(" OFFSET ");
+        builder.push_bind(query.offset);
+    }
+}
+
 impl OrderService {
+    /// 统计符合过滤条件的订单总数，忽略 limit/offset
+    pub async fn count_orders(&self, query: &OrderQuery<'_>) -> Result<i64, sqlx::Error> {
+        let mut builder = QueryBuilder::new("SELECT count(*) FROM jidan.orders WHERE 1=1");
+
+        apply_filters(&mut builder, query);
+
+        builder
+            .build_query_scalar::<i64>()
+            .fetch_one(&self.pool)
+            .await
+    }
+
     pub async fn query_orders(
         &self,
         query: OrderQuery<'_>,
+    ) -> Result<Vec<OrderSummary>, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        self.query_orders_tx(query, &mut conn).await
+    }
+
+    /// 与 [`OrderService::query_orders`] 相同，但在调用方的连接或事务中执行，可读到事务内未提交的修改
+    pub async fn query_orders_tx(
+        &self,
+        query: OrderQuery<'_>,
+        conn: &mut PgConnection,
     ) -> Result<Vec<OrderSummary>, sqlx::Error> {
         let mut builder = QueryBuilder::new(
             r#"
             SELECT
                 id, user_id, status, channel, channel_no,
                 total_items_amount, payable_amount, paid_amount, refunded_amount,
-                created_at, expire_at
+                created_at, updated_at, expire_at
             FROM jidan.orders
             WHERE 1=1
             "#,
@@ -157,20 +319,11 @@ impl OrderService {
 
         apply_filters(&mut builder, &query);
 
-        builder.push(" ORDER BY created_at DESC");
-
-        if let Some(limit) = query.limit {
-            builder.push(" LIMIT ");
-            builder.push_bind(limit);
-        }
-        if query.offset > 0 {
-            builder.push(" OFFSET ");
-            builder.push_bind(query.offset);
-        }
+        apply_pagination(&mut builder, &query);
 
         builder
             .build_query_as::<OrderSummary>()
-            .fetch_all(&self.pool)
+            .fetch_all(&mut *conn)
             .await
     }
 
@@ -194,19 +347,41 @@ impl OrderService {
 
         apply_filters(&mut builder, &query);
 
-        builder.push(" ORDER BY created_at DESC");
-
-        if let Some(limit) = query.limit {
-            builder.push(" LIMIT ");
-            builder.push_bind(limit);
-        }
-        if query.offset > 0 {
-            builder.push(" OFFSET ");
-            builder.push_bind(query.offset);
-        }
+        apply_pagination(&mut builder, &query);
 
         let orders_rows = builder.build().fetch_all(&self.pool).await?;
 
+        self.attach_items(orders_rows).await
+    }
+
+    /// 按 id 批量查询订单详情，不存在的 id 会被忽略
+    pub async fn get_order_details_by_ids(
+        &self,
+        ids: &[Uuid],
+    ) -> Result<Vec<OrderDetail>, sqlx::Error> {
+        let orders_rows = sqlx::query(
+            r#"
+            SELECT
+                id, user_id, channel, channel_no, status,
+                total_items_amount, payment_fee, discount_amount,
+                payable_amount, paid_amount, refunded_amount,
+                refund_fee,
+                created_at, updated_at, expire_at,
+                extra_info
+            FROM jidan.orders
+            WHERE id = ANY($1)
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        self.attach_items(orders_rows).await
+    }
+
+    /// 批量加载订单行对应的物品，并组装为 [`OrderDetail`]
+    async fn attach_items(&self, orders_rows: Vec<PgRow>) -> Result<Vec<OrderDetail>, sqlx::Error> {
         if orders_rows.is_empty() {
             return Ok(vec![]);
         }
@@ -214,7 +389,7 @@ impl OrderService {
         let order_ids: Vec<Uuid> = orders_rows.iter().map(|row| row.get("id")).collect();
 
         // Batch fetch items
-        let items_rows = sqlx::query(
+        let items_rows = sqlx::query_as::<_, OrderItemRow>(
             r#"
             SELECT
                 id, item_id, item_type, original_price, unit_price, real_amount, extra_info, order_id
@@ -229,17 +404,7 @@ impl OrderService {
         let mut items_map: HashMap<Uuid, Vec<OrderItemDetail>> = HashMap::new();
 
         for row in items_rows {
-            let order_id: Uuid = row.get("order_id");
-            let item = OrderItemDetail {
-                id: row.get("id"),
-                item_id: row.get("item_id"),
-                item_type: row.get("item_type"),
-                original_price: row.get("original_price"),
-                unit_price: row.get("unit_price"),
-                real_amount: row.get("real_amount"),
-                extra_info: row.try_get("extra_info").unwrap_or(None),
-            };
-            items_map.entry(order_id).or_default().push(item);
+            items_map.entry(row.order_id).or_default().push(row.item);
         }
 
         let mut results = Vec::with_capacity(orders_rows.len());
@@ -307,9 +472,58 @@ impl OrderService {
         .await
     }
 
+    /// 仅查询订单汇总信息，不加载物品
+    pub async fn get_order_summary_by_id(
+        &self,
+        id: Uuid,
+    ) -> Result<Option<OrderSummary>, sqlx::Error> {
+        sqlx::query_as::<_, OrderSummary>(
+            r#"
+            SELECT
+                id, user_id, status, channel, channel_no,
+                total_items_amount, payable_amount, paid_amount, refunded_amount,
+                created_at, updated_at, expire_at
+            FROM jidan.orders
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// 批量查询订单汇总信息，结果按 `ids` 的顺序返回，不存在的 id 被忽略
+    pub async fn get_orders_by_ids(&self, ids: &[Uuid]) -> Result<Vec<OrderSummary>, sqlx::Error> {
+        sqlx::query_as::<_, OrderSummary>(
+            r#"
+            SELECT
+                id, user_id, status, channel, channel_no,
+                total_items_amount, payable_amount, paid_amount, refunded_amount,
+                created_at, updated_at, expire_at
+            FROM jidan.orders
+            WHERE id = ANY($1)
+            ORDER BY array_position($1, id)
+            "#,
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await
+    }
+
     pub async fn get_order_detail_by_id(
         &self,
         id: Uuid,
+    ) -> Result<Option<OrderDetail>, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        self.get_order_detail_by_id_tx(id, &mut conn).await
+    }
+
+    /// 与 [`OrderService::get_order_detail_by_id`] 相同，但在调用方的连接或事务中执行
+    pub async fn get_order_detail_by_id_tx(
+        &self,
+        id: Uuid,
+        conn: &mut PgConnection,
     ) -> Result<Option<OrderDetail>, sqlx::Error> {
         let row = sqlx::query(
             r#"
@@ -325,7 +539,7 @@ impl OrderService {
             "#,
         )
         .bind(id)
-        .fetch_optional(&self.pool)
+        .fetch_optional(&mut *conn)
         .await?;
 
         let row = match row {
@@ -342,7 +556,7 @@ impl OrderService {
             "#,
         )
         .bind(id)
-        .fetch_all(&self.pool)
+        .fetch_all(&mut *conn)
         .await?;
 
         Ok(Some(OrderDetail {
@@ -428,6 +642,7 @@ impl OrderService {
     }
 
     /// 获取创建于 [begin, end) 内的订单
+    #[deprecated(note = "loads every order in the window, use `get_orders_created_in_paged`")]
     pub async fn get_orders_created_in(
         &self,
         begin: time::OffsetDateTime,
@@ -442,6 +657,53 @@ impl OrderService {
         .await
     }
 
+    /// 分页获取创建于 [begin, end) 内的订单，同时返回符合条件的订单总数
+    /// `page` 从 1 开始，超出范围的 `page` 与 `page_size` 按 [`OrderQuery::page`] 修正
+    pub async fn get_orders_created_in_paged(
+        &self,
+        begin: time::OffsetDateTime,
+        end: time::OffsetDateTime,
+        page: i64,
+        page_size: i64,
+        sort: OrderSort,
+    ) -> Result<(Vec<OrderSummary>, i64), sqlx::Error> {
+        let query = OrderQuery::new()
+            .created_after(begin)
+            .created_before(end)
+            .sort(sort)
+            .page(page, page_size);
+
+        let total = self.count_orders(&query).await?;
+        let orders = self.query_orders(query).await?;
+
+        Ok((orders, total))
+    }
+
+    /// 按渠道汇总创建于 [begin, end) 内订单的已付/已退款金额
+    pub async fn channel_totals(
+        &self,
+        begin: time::OffsetDateTime,
+        end: time::OffsetDateTime,
+    ) -> Result<Vec<ChannelTotals>, sqlx::Error> {
+        sqlx::query_as::<_, ChannelTotals>(
+            r#"
+            SELECT
+                channel,
+                count(*) AS order_count,
+                COALESCE(SUM(paid_amount), 0)::int8 AS paid_amount,
+                COALESCE(SUM(refunded_amount), 0)::int8 AS refunded_amount
+            FROM jidan.orders
+            WHERE created_at >= $1 AND created_at < $2
+            GROUP BY channel
+            ORDER BY channel
+            "#,
+        )
+        .bind(begin)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await
+    }
+
     /// 获取所有含有给定物品的订单
     pub async fn get_orders_of_items(
         &self,
@@ -451,6 +713,26 @@ impl OrderService {
             .await
     }
 
+    /// 获取所有含有给定物品且处于指定状态的订单，如用于权限校验
+    pub async fn get_orders_of_items_with_status(
+        &self,
+        item_ids: &[Uuid],
+        status: OrderStatus,
+    ) -> Result<Vec<OrderSummary>, sqlx::Error> {
+        // has_items 为空时不做过滤，这里需显式返回空以免匹配到所有订单
+        if item_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.query_orders(
+            OrderQuery::new()
+                .has_items(item_ids)
+                .status(status)
+                .limit(None),
+        )
+        .await
+    }
+
     /// 获取所有含有给定物品的订单
     pub async fn get_order_id_map_of_items(
         &self,
@@ -472,6 +754,17 @@ impl OrderService {
     pub async fn get_items_of_order(
         &self,
         order_id: Uuid,
+    ) -> Result<Vec<OrderItemDetail>, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        self.get_items_of_order_tx(order_id, &mut conn).await
+    }
+
+    /// 与 [`OrderService::get_items_of_order`] 相同，但在调用方的连接或事务中执行
+    pub async fn get_items_of_order_tx(
+        &self,
+        order_id: Uuid,
+        conn: &mut PgConnection,
     ) -> Result<Vec<OrderItemDetail>, sqlx::Error> {
         sqlx::query_as::<_, OrderItemDetail>(
             r#"
@@ -482,7 +775,7 @@ impl OrderService {
             "#,
         )
         .bind(order_id)
-        .fetch_all(&self.pool)
+        .fetch_all(&mut *conn)
         .await
     }
 
@@ -521,6 +814,23 @@ impl OrderService {
         .await
     }
 
+    pub async fn get_item_by_id(
+        &self,
+        order_item_id: Uuid,
+    ) -> Result<Option<OrderItemDetail>, sqlx::Error> {
+        sqlx::query_as::<_, OrderItemDetail>(
+            r#"
+            SELECT
+                id, item_id, item_type, original_price, unit_price, real_amount, extra_info
+            FROM jidan.order_items
+            WHERE id = $1
+            "#,
+        )
+        .bind(order_item_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
     pub async fn find_all_by_extra_info(
         &self,
         extra_info: &Value,
@@ -551,3 +861,26 @@ impl OrderService {
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_clamps_out_of_range_values() {
+        let query = OrderQuery::new().page(3, 20);
+        assert_eq!((query.offset, query.limit), (40, Some(20)));
+
+        let query = OrderQuery::new().page(0, 20);
+        assert_eq!((query.offset, query.limit), (0, Some(20)));
+
+        let query = OrderQuery::new().page(2, 0);
+        assert_eq!((query.offset, query.limit), (1, Some(1)));
+
+        let query = OrderQuery::new().page(-5, -10);
+        assert_eq!((query.offset, query.limit), (0, Some(1)));
+
+        let query = OrderQuery::new().page(i64::MAX, i64::MAX);
+        assert_eq!((query.offset, query.limit), (i64::MAX, Some(i64::MAX)));
+    }
+}
@@ -1,9 +1,21 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
-use s3::{Bucket, Region, creds::Credentials};
+use s3::{Bucket, Region};
 use sqlx::PgPool;
 
-use crate::Kufang;
+use uuid::Uuid;
+
+use crate::{AliyunOss, KeyStrategy, Kufang, MimeCheck, StorageProvider};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BuilderError {
+    /// 缺少必填项：`bucket`、`credentials`、`pool` 或 `key_prefix`
+    #[error("missing required field: {0}")]
+    MissingField(&'static str),
+
+    #[error("failed to create s3 bucket: {0}")]
+    Bucket(#[from] s3::error::S3Error),
+}
 
 impl Kufang {
     pub fn builder() -> KufangBuilder {
@@ -11,15 +23,23 @@ impl Kufang {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct KufangBuilder {
     bucket_name: Option<String>,
     bucket_region: Option<String>,
     bucket_endpoint: Option<String>,
     bucket_access_key: Option<String>,
     bucket_access_secret: Option<String>,
+    bucket_session_token: Option<String>,
     db_pool: Option<PgPool>,
     s3_key_prefix: Option<String>,
+    key_strategy: Option<KeyStrategy>,
+    max_upload_size: Option<u32>,
+    post_policy_expiry_secs: Option<u32>,
+    max_callback_body_size: Option<usize>,
+    storage_provider: Option<Arc<dyn StorageProvider>>,
+    allowed_mimes: Option<Vec<String>>,
+    mime_check: Option<MimeCheck>,
 }
 
 impl KufangBuilder {
@@ -47,6 +67,13 @@ impl KufangBuilder {
         self
     }
 
+    /// STS 临时凭证的 token，过期前可通过 [`Kufang::set_credentials`] 刷新
+    pub fn session_token(mut self, token: impl Into<String>) -> Self {
+        self.bucket_session_token = Some(token.into());
+
+        self
+    }
+
     pub fn pool(mut self, pool: PgPool) -> Self {
         self.db_pool = Some(pool);
 
@@ -59,27 +86,120 @@ impl KufangBuilder {
         self
     }
 
-    pub fn build(self) -> Kufang {
+    /// 自定义 S3 对象路径，替代默认的 `{prefix}/{public|private}/{year}/{month}/{day}/{id}`
+    /// 设置后无需再设置 [`KufangBuilder::key_prefix`]
+    pub fn key_strategy(
+        mut self,
+        strategy: impl Fn(Uuid, bool) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.key_strategy = Some(Arc::new(strategy));
+
+        self
+    }
+
+    /// 直传允许的最大文件大小（字节），默认 100M
+    pub fn max_upload_size(mut self, bytes: u32) -> Self {
+        self.max_upload_size = Some(bytes);
+
+        self
+    }
+
+    /// 直传 policy 的有效期（秒），默认 30 秒
+    pub fn post_policy_expiry(mut self, secs: u32) -> Self {
+        self.post_policy_expiry_secs = Some(secs);
+
+        self
+    }
+
+    /// 上传回调 body 的最大长度（字节），超出时不做验签和解析，默认 256K
+    pub fn max_callback_body_size(mut self, bytes: usize) -> Self {
+        self.max_callback_body_size = Some(bytes);
+
+        self
+    }
+
+    /// 存储后端，默认为 [`AliyunOss`]
+    pub fn storage_provider(mut self, provider: impl StorageProvider + 'static) -> Self {
+        self.storage_provider = Some(Arc::new(provider));
+
+        self
+    }
+
+    /// 允许上传的文件类型，按前缀匹配，如 `image/`；默认不限制
+    ///
+    /// 直传时以所有类型的公共前缀作为 policy 中 `Content-Type` 的 `starts-with` 条件
+    pub fn allowed_mimes(mut self, mimes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.allowed_mimes = Some(mimes.into_iter().map(Into::into).collect());
+
+        self
+    }
+
+    /// [`Kufang::upload_file`] 时根据文件开头的字节校验声明的类型，默认信任调用方传入的类型
+    ///
+    /// 流式上传和直传不经过此校验
+    pub fn mime_check(mut self, mode: MimeCheck) -> Self {
+        self.mime_check = Some(mode);
+
+        self
+    }
+
+    /// 缺少必填项时返回 [`BuilderError::MissingField`]
+    pub fn build(self) -> Result<Kufang, BuilderError> {
+        let (Some(bucket_name), Some(region), Some(endpoint)) =
+            (self.bucket_name, self.bucket_region, self.bucket_endpoint)
+        else {
+            return Err(BuilderError::MissingField("bucket"));
+        };
+        let (Some(access_key), Some(secret_key)) =
+            (self.bucket_access_key, self.bucket_access_secret)
+        else {
+            return Err(BuilderError::MissingField("credentials"));
+        };
+        let pool = self.db_pool.ok_or(BuilderError::MissingField("pool"))?;
+        // 自定义路径时不需要前缀
+        let s3_key_prefix = match self.s3_key_prefix {
+            Some(prefix) => prefix,
+            None if self.key_strategy.is_some() => String::new(),
+            None => return Err(BuilderError::MissingField("key_prefix")),
+        };
+
         let bucket = Bucket::new(
-            &self.bucket_name.unwrap(),
-            Region::Custom {
-                region: self.bucket_region.unwrap(),
-                endpoint: self.bucket_endpoint.unwrap(),
-            },
-            Credentials {
-                access_key: Some(self.bucket_access_key.unwrap()),
-                secret_key: Some(self.bucket_access_secret.unwrap()),
-                security_token: None,
-                session_token: None,
-                expiration: None,
-            },
-        )
-        .expect("create s3 bucket");
-
-        Kufang {
-            bucket: Arc::from(bucket),
-            pool: self.db_pool.unwrap(),
-            s3_key_prefix: self.s3_key_prefix.unwrap().into(),
-        }
+            &bucket_name,
+            Region::Custom { region, endpoint },
+            crate::credentials(access_key, secret_key, self.bucket_session_token),
+        )?;
+
+        Ok(Kufang {
+            bucket: Arc::new(RwLock::new(Arc::from(bucket))),
+            pool,
+            s3_key_prefix: s3_key_prefix.into(),
+            key_strategy: self.key_strategy,
+            max_upload_size: self.max_upload_size.unwrap_or(100 * 1024 * 1024),
+            post_policy_expiry_secs: self.post_policy_expiry_secs.unwrap_or(30),
+            max_callback_body_size: self.max_callback_body_size.unwrap_or(256 * 1024),
+            storage_provider: self.storage_provider.unwrap_or_else(|| Arc::new(AliyunOss)),
+            allowed_mimes: self.allowed_mimes.map(Into::into),
+            mime_check: self.mime_check,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_reports_missing_field() {
+        assert!(matches!(
+            Kufang::builder().build(),
+            Err(BuilderError::MissingField("bucket"))
+        ));
+        assert!(matches!(
+            Kufang::builder()
+                .bucket("bucket", "region", "http://localhost")
+                .credentials("key", "secret")
+                .build(),
+            Err(BuilderError::MissingField("pool"))
+        ));
     }
 }
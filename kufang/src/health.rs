@@ -0,0 +1,49 @@
+use crate::Kufang;
+
+/// [`Kufang::health_check`] 的失败原因，区分不可用的依赖
+#[derive(Debug, thiserror::Error)]
+pub enum HealthError {
+    #[error("database unavailable: {0}")]
+    Database(sqlx::Error),
+
+    #[error("storage unavailable: {0}")]
+    Storage(s3::error::S3Error),
+
+    #[error("database unavailable: {database}; storage unavailable: {storage}")]
+    Both {
+        database: sqlx::Error,
+        storage: s3::error::S3Error,
+    },
+}
+
+impl Kufang {
+    /// 检查数据库与存储桶是否可用，两者同时检查
+    /// 存储桶通过在 key 前缀下列出至多一个对象检查，需要 ListBucket 权限
+    pub async fn health_check(&self) -> Result<(), HealthError> {
+        let database = async {
+            sqlx::query("SELECT 1")
+                .execute(&self.pool)
+                .await
+                .map(|_| ())
+        };
+
+        let storage = async {
+            let bucket = self.bucket();
+            let (_, status) = bucket
+                .list_page(self.s3_key_prefix.to_string(), None, None, None, Some(1))
+                .await?;
+
+            match status {
+                200..=299 => Ok(()),
+                code => Err(s3::error::S3Error::HttpFailWithBody(code, String::new())),
+            }
+        };
+
+        match tokio::join!(database, storage) {
+            (Ok(()), Ok(())) => Ok(()),
+            (Err(database), Ok(())) => Err(HealthError::Database(database)),
+            (Ok(()), Err(storage)) => Err(HealthError::Storage(storage)),
+            (Err(database), Err(storage)) => Err(HealthError::Both { database, storage }),
+        }
+    }
+}
@@ -1,24 +1,247 @@
 use md5::{Digest, Md5};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 use uuid::Uuid;
 
-use s3::Bucket;
+use s3::{Bucket, creds::Credentials};
 
 mod builder;
+mod health;
 pub mod migration;
 mod post;
+mod provider;
+mod sniff;
+mod stream;
 
-pub use post::PostObjectUrl;
+pub use builder::BuilderError;
+pub use health::HealthError;
+pub use post::{CallbackError, PostObjectUrl};
+pub use provider::{AliyunOss, GenericS3, StorageProvider};
+pub use sniff::MimeCheck;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+
+    #[error(transparent)]
+    S3(#[from] s3::error::S3Error),
+
+    /// 文件类型不在构建时 `allowed_mimes` 允许的范围内
+    #[error("mime type not allowed: {0}")]
+    MimeNotAllowed(String),
+
+    /// 文件内容与声明的类型不一致，见 [`MimeCheck::Reject`]
+    #[error("declared mime {declared} does not match detected {detected:?}")]
+    MimeMismatch {
+        declared: String,
+        detected: Option<&'static str>,
+    },
+
+    /// 该 file_id 已登记为其他对象或已被引用，不能覆盖
+    #[error("file {0} is already registered")]
+    FileConflict(Uuid),
+
+    /// 调用方指定的 file_id 不是 uuid v7
+    #[error("file id {0} is not a uuid v7")]
+    InvalidFileId(Uuid),
+}
+
+/// 上传时附加的对象请求头，名称或值不合法时返回错误
+fn object_headers(
+    headers: Option<&HashMap<String, String>>,
+) -> Result<http::HeaderMap, s3::error::S3Error> {
+    let mut map = http::HeaderMap::new();
+
+    for (name, value) in headers.into_iter().flatten() {
+        map.insert(
+            http::HeaderName::from_bytes(name.as_bytes())?,
+            value
+                .parse()
+                .map_err(s3::error::S3Error::InvalidHeaderValue)?,
+        );
+    }
+
+    Ok(map)
+}
+
+/// 未开启 rust-s3 的 fail-on-err，需要手动将非 2xx 响应转为错误
+fn check_s3_status(resp: s3::request::ResponseData) -> Result<(), s3::error::S3Error> {
+    match resp.status_code() {
+        200..=299 => Ok(()),
+        code => Err(s3::error::S3Error::HttpFailWithBody(
+            code,
+            resp.to_string().unwrap_or_default(),
+        )),
+    }
+}
+
+/// 默认对象路径与 [`Kufang::collect_garbage`] 依赖 uuid v7 中的时间戳，调用方指定的 file_id 需先校验
+fn check_file_id(id: Uuid) -> Result<(), Error> {
+    match id.get_version() {
+        Some(uuid::Version::SortRand) => Ok(()),
+        _ => Err(Error::InvalidFileId(id)),
+    }
+}
+
+/// OSS 直传 policy 中只会带上 `security_token`，因此 STS token 同时填入两处
+fn credentials(
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+) -> Credentials {
+    Credentials {
+        access_key: Some(access_key),
+        secret_key: Some(secret_key),
+        security_token: session_token.clone(),
+        session_token,
+        expiration: None,
+    }
+}
+
+/// 按 RFC 6266 生成 `attachment` 的 Content-Disposition，
+/// 非 ASCII 文件名通过 `filename*` 以 UTF-8 百分号编码，`filename` 中替换为 `_` 作为兼容
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct FileRecord {
+    pub id: Uuid,
+    pub s3_key: String,
+    pub size: i64,
+    pub md5: Vec<u8>,
+    pub mime: String,
+    pub ref_count: i32,
+    pub public: bool,
+    /// 上传时的原始文件名
+    pub filename: Option<String>,
+}
+
+/// 由文件 id 与是否公开生成 S3 对象路径，见 `KufangBuilder::key_strategy`
+pub type KeyStrategy = Arc<dyn Fn(Uuid, bool) -> String + Send + Sync>;
+
+/// [`Kufang::verify_file`] 的检查结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileIntegrity {
+    pub db_present: bool,
+    pub s3_present: bool,
+    /// 数据库记录与 S3 对象均存在且大小一致
+    pub size_matches: bool,
+}
 
 #[derive(Clone)]
 pub struct Kufang {
-    bucket: Arc<Bucket>,
+    /// 凭证可在运行时替换，见 [`Kufang::set_credentials`]
+    bucket: Arc<RwLock<Arc<Bucket>>>,
     pool: sqlx::PgPool,
     s3_key_prefix: Arc<str>,
+    key_strategy: Option<KeyStrategy>,
+    max_upload_size: u32,
+    post_policy_expiry_secs: u32,
+    max_callback_body_size: usize,
+    storage_provider: Arc<dyn StorageProvider>,
+    allowed_mimes: Option<Arc<[String]>>,
+    mime_check: Option<MimeCheck>,
 }
 
 impl Kufang {
+    fn bucket(&self) -> Arc<Bucket> {
+        self.bucket.read().expect("bucket lock poisoned").clone()
+    }
+
+    /// 替换访问凭证，用于 STS 临时凭证过期前的刷新，对所有克隆出的 [`Kufang`] 生效
+    pub fn set_credentials(
+        &self,
+        access_key: impl Into<String>,
+        access_secret: impl Into<String>,
+        session_token: Option<String>,
+    ) {
+        let mut bucket = self.bucket.write().expect("bucket lock poisoned");
+
+        let mut new_bucket = Bucket::clone(&bucket);
+        new_bucket.set_credentials(credentials(
+            access_key.into(),
+            access_secret.into(),
+            session_token,
+        ));
+
+        *bucket = Arc::new(new_bucket);
+    }
+
+    fn check_mime(&self, mime: &str) -> Result<(), Error> {
+        match &self.allowed_mimes {
+            Some(allowed)
+                if !allowed
+                    .iter()
+                    .any(|prefix| mime.starts_with(prefix.as_str())) =>
+            {
+                Err(Error::MimeNotAllowed(mime.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 按 `mime_check` 校验文件内容后再检查是否允许，返回应当保存的类型
+    fn resolve_mime<'a>(&self, file: &[u8], mime: &'a str) -> Result<&'a str, Error> {
+        let mime = match self.mime_check {
+            Some(mode) => sniff::check_content(file, mime, mode)?,
+            None => mime,
+        };
+
+        self.check_mime(mime)?;
+
+        Ok(mime)
+    }
+
+    /// 允许的类型的公共前缀，用于直传 policy 的 `starts-with` 条件
+    fn allowed_mime_prefix(&self) -> Option<&str> {
+        let allowed = self.allowed_mimes.as_deref()?;
+        let (first, rest) = allowed.split_first()?;
+
+        let mut len = rest.iter().fold(first.len(), |len, mime| {
+            first
+                .bytes()
+                .zip(mime.bytes())
+                .take(len)
+                .take_while(|(a, b)| a == b)
+                .count()
+        });
+        while !first.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        Some(&first[..len]).filter(|prefix| !prefix.is_empty())
+    }
+
     fn get_s3_key(&self, file_id: Uuid, public: bool) -> String {
+        if let Some(strategy) = &self.key_strategy {
+            return strategy(file_id, public);
+        }
+
+        // 默认路径：{prefix}/{public|private}/{year}/{month}/{day}/{id}
         let time = {
             let time = file_id
                 .get_timestamp()
@@ -44,39 +267,168 @@ impl Kufang {
         )
     }
 
-    pub async fn upload_file(&self, file: &[u8], mime: &str, public: bool) -> Uuid {
-        let id = Uuid::now_v7();
+    /// 上传文件，返回文件 id，返回的文件已为调用方计入一次引用，无需再调用 [`Kufang::increment_ref`]
+    /// 默认按 md5、public 与 mime 去重：已存在相同内容的文件时跳过上传，
+    /// 在同一条语句中增加其引用计数并返回已有的 id，因此不会在调用方拿到 id 之前被删除；
+    /// 已有文件没有文件名时补上 `filename`，否则保留原文件名，需要时可在 [`Kufang::get_download_url`] 中指定下载文件名
+    /// `force` 为 `true` 时总是上传新文件
+    ///
+    /// 并发上传相同内容时由唯一索引保证只登记一条记录，其余请求返回同一个 id 并删除各自上传的对象
+    pub async fn upload_file(
+        &self,
+        file: &[u8],
+        mime: &str,
+        filename: Option<&str>,
+        public: bool,
+        force: bool,
+    ) -> Result<Uuid, Error> {
+        let mime = self.resolve_mime(file, mime)?;
+
+        if !force {
+            let md5 = Md5::digest(file);
 
-        self.upload_file_with_id(id, file, mime, public).await;
+            let existing = sqlx::query_scalar::<_, Uuid>(
+                "
+                UPDATE kufang.files
+                SET ref_count = ref_count + 1, filename = COALESCE(filename, $4)
+                WHERE md5 = $1 AND public = $2 AND mime = $3 AND dedup
+                RETURNING id
+                ",
+            )
+            .bind(md5.as_slice())
+            .bind(public)
+            .bind(mime)
+            .bind(filename)
+            .fetch_optional(&self.pool)
+            .await?;
+
+            if let Some(id) = existing {
+                return Ok(id);
+            }
+        }
+
+        self.put_file(
+            Uuid::now_v7(),
+            file,
+            mime,
+            filename,
+            public,
+            None,
+            !force,
+            1,
+        )
+        .await
+    }
+
+    /// `headers` 按原样作为对象的请求头写入，如 `x-amz-meta-owner`、`cache-control`
+    /// errors:
+    /// - `id` 不是 uuid v7 时返回 [`Error::InvalidFileId`]
+    /// - `id` 已登记时返回 [`Error::FileConflict`]，不会上传对象
+    pub async fn upload_file_with_id(
+        &self,
+        id: Uuid,
+        file: &[u8],
+        mime: &str,
+        filename: Option<&str>,
+        public: bool,
+        headers: Option<&HashMap<String, String>>,
+    ) -> Result<(), Error> {
+        let mime = self.resolve_mime(file, mime)?;
+
+        self.check_new_file_id(id).await?;
+
+        self.put_file(id, file, mime, filename, public, headers, false, 0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// 调用方指定的 id 需在上传对象之前检查，否则会先覆盖已登记文件的对象，之后登记时才因主键冲突失败
+    async fn check_new_file_id(&self, id: Uuid) -> Result<(), Error> {
+        check_file_id(id)?;
 
-        id
+        let exists = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM kufang.files WHERE id = $1)",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if exists {
+            return Err(Error::FileConflict(id));
+        }
+
+        Ok(())
     }
 
-    pub async fn upload_file_with_id(&self, id: Uuid, file: &[u8], mime: &str, public: bool) {
+    /// 上传对象并登记文件，返回登记的文件 id，`ref_count` 为登记时计入的引用数
+    /// `dedup` 为 `true` 时纳入 md5、public 与 mime 的唯一索引，
+    /// 冲突时删除刚上传的对象，将 `ref_count` 累加到已有的文件上并返回其 id
+    #[allow(clippy::too_many_arguments)]
+    async fn put_file(
+        &self,
+        id: Uuid,
+        file: &[u8],
+        mime: &str,
+        filename: Option<&str>,
+        public: bool,
+        headers: Option<&HashMap<String, String>>,
+        dedup: bool,
+        ref_count: i32,
+    ) -> Result<Uuid, Error> {
         let key = self.get_s3_key(id, public);
 
         let md5 = Md5::digest(file).to_vec();
 
-        self.bucket
-            .put_object_with_content_type(&key, file, mime)
-            .await
-            .unwrap();
+        check_s3_status(
+            self.bucket()
+                .put_object_builder(&key, file)
+                .with_content_type(mime)
+                .with_headers(object_headers(headers)?)
+                .execute()
+                .await?,
+        )?;
 
-        sqlx::query(
+        let stored = sqlx::query_scalar::<_, Uuid>(
             "
-            INSERT INTO kufang.files (id, s3_key, size, md5, mime, ref_count, public)
-            VALUES ($1, $2, $3, $4, $5, 0, $6)
+            INSERT INTO kufang.files
+                (id, s3_key, size, md5, mime, ref_count, public, filename, dedup)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (md5, public, mime) WHERE dedup DO UPDATE SET
+                ref_count = kufang.files.ref_count + EXCLUDED.ref_count,
+                filename = COALESCE(kufang.files.filename, EXCLUDED.filename)
+            RETURNING id
             ",
         )
         .bind(id)
-        .bind(key)
+        .bind(&key)
         .bind(file.len() as i64)
         .bind(md5)
         .bind(mime)
+        .bind(ref_count)
         .bind(public)
-        .execute(&self.pool)
+        .bind(filename)
+        .bind(dedup)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if stored != id {
+            let _ = self.bucket().delete_object(&key).await;
+        }
+
+        Ok(stored)
+    }
+
+    pub async fn get_file(&self, id: Uuid) -> Result<Option<FileRecord>, sqlx::Error> {
+        sqlx::query_as::<_, FileRecord>(
+            "
+            SELECT id, s3_key, size, md5, mime, ref_count, public, filename
+            FROM kufang.files WHERE id = $1
+            ",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
         .await
-        .unwrap();
     }
 
     pub async fn get_file_s3_key(&self, id: Uuid) -> Result<Option<String>, sqlx::Error> {
@@ -86,6 +438,50 @@ impl Kufang {
             .await
     }
 
+    /// 获取文件的下载地址，文件不存在时返回 `None`
+    /// 公开文件直接返回对象地址，私有文件返回有效期为 `expiry_secs` 秒的预签名地址
+    ///
+    /// 指定 `filename` 时通过 `response-content-disposition` 让浏览器以该文件名下载，
+    /// 此时公开文件也会返回预签名地址
+    pub async fn get_download_url(
+        &self,
+        id: Uuid,
+        expiry_secs: u32,
+        filename: Option<&str>,
+    ) -> Result<Option<String>, Error> {
+        let file = sqlx::query_as::<_, (String, bool)>(
+            "SELECT s3_key, public FROM kufang.files WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some((key, public)) = file else {
+            return Ok(None);
+        };
+
+        let Some(filename) = filename else {
+            if public {
+                return Ok(Some(format!("{}/{}", self.bucket().url(), key)));
+            }
+
+            return Ok(Some(
+                self.bucket().presign_get(&key, expiry_secs, None).await?,
+            ));
+        };
+
+        let queries = HashMap::from([(
+            "response-content-disposition".to_string(),
+            content_disposition(filename),
+        )]);
+
+        Ok(Some(
+            self.bucket()
+                .presign_get(&key, expiry_secs, Some(queries))
+                .await?,
+        ))
+    }
+
     pub async fn get_s3_key_map(&self, ids: &[Uuid]) -> Result<HashMap<Uuid, String>, sqlx::Error> {
         sqlx::query_as::<_, (Uuid, String)>(
             "SELECT id, s3_key FROM kufang.files WHERE id = ANY($1::uuid[])",
@@ -96,10 +492,371 @@ impl Kufang {
         .map(|r| r.into_iter().collect::<HashMap<_, _>>())
     }
 
+    /// 批量获取文件信息，按 `ids` 的顺序返回，不存在的 id 被忽略
+    pub async fn get_files_by_ids(&self, ids: &[Uuid]) -> Result<Vec<FileRecord>, sqlx::Error> {
+        sqlx::query_as::<_, FileRecord>(
+            "
+            SELECT id, s3_key, size, md5, mime, ref_count, public, filename
+            FROM kufang.files WHERE id = ANY($1::uuid[])
+            ORDER BY array_position($1::uuid[], id)
+            ",
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await
+    }
+
     pub async fn get_file_id_by_md5(&self, md5: &[u8]) -> Result<Option<Uuid>, sqlx::Error> {
         sqlx::query_scalar::<_, Uuid>("SELECT id FROM kufang.files WHERE md5 = $1")
             .bind(md5)
             .fetch_optional(&self.pool)
             .await
     }
+
+    /// 批量按 md5 查找文件 id，同一 md5 有多个文件时取最早上传的
+    pub async fn get_file_ids_by_md5(
+        &self,
+        md5s: &[Vec<u8>],
+    ) -> Result<HashMap<Vec<u8>, Uuid>, sqlx::Error> {
+        sqlx::query_as::<_, (Vec<u8>, Uuid)>(
+            "
+            SELECT DISTINCT ON (md5) md5, id FROM kufang.files
+            WHERE md5 = ANY($1::bytea[])
+            ORDER BY md5, id
+            ",
+        )
+        .bind(md5s)
+        .fetch_all(&self.pool)
+        .await
+        .map(|r| r.into_iter().collect::<HashMap<_, _>>())
+    }
+
+    /// 引用计数加一，返回新的引用计数
+    pub async fn increment_ref(&self, id: Uuid) -> Result<i32, sqlx::Error> {
+        sqlx::query_scalar::<_, i32>(
+            "UPDATE kufang.files SET ref_count = ref_count + 1 WHERE id = $1 RETURNING ref_count",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// 引用计数减一，返回新的引用计数
+    pub async fn decrement_ref(&self, id: Uuid) -> Result<i32, sqlx::Error> {
+        sqlx::query_scalar::<_, i32>(
+            "UPDATE kufang.files SET ref_count = ref_count - 1 WHERE id = $1 RETURNING ref_count",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// 检查数据库记录与 S3 对象是否一致，用于存储的对账
+    /// 数据库中没有记录时，按公开与私有两种路径查找对象
+    pub async fn verify_file(&self, id: Uuid) -> Result<FileIntegrity, Error> {
+        let file = sqlx::query_as::<_, (String, i64)>(
+            "SELECT s3_key, size FROM kufang.files WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some((key, size)) = file else {
+            let mut s3_present = false;
+            for public in [true, false] {
+                if self
+                    .head_size(&self.get_s3_key(id, public))
+                    .await?
+                    .is_some()
+                {
+                    s3_present = true;
+                    break;
+                }
+            }
+
+            return Ok(FileIntegrity {
+                db_present: false,
+                s3_present,
+                size_matches: false,
+            });
+        };
+
+        let s3_size = self.head_size(&key).await?;
+
+        Ok(FileIntegrity {
+            db_present: true,
+            s3_present: s3_size.is_some(),
+            size_matches: s3_size == Some(size),
+        })
+    }
+
+    /// HEAD 对象，返回对象大小，对象不存在时返回 `None`
+    async fn head_size(&self, key: &str) -> Result<Option<i64>, Error> {
+        let (head, status) = self.bucket().head_object(key).await?;
+        match status {
+            404 => Ok(None),
+            200..=299 => Ok(Some(head.content_length.unwrap_or_default())),
+            code => Err(s3::error::S3Error::HttpFailWithBody(code, String::new()).into()),
+        }
+    }
+
+    /// 修改文件的公开状态，将 S3 对象复制到新的路径并删除旧对象
+    /// 返回是否修改，文件不存在或状态未变化时返回 `false`
+    ///
+    /// 修改后的文件不再参与 [`Kufang::upload_file`] 的去重，避免与另一份相同内容的文件冲突
+    pub async fn set_visibility(&self, id: Uuid, public: bool) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let file = sqlx::query_as::<_, (String, bool)>(
+            "SELECT s3_key, public FROM kufang.files WHERE id = $1 FOR UPDATE",
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some((old_key, old_public)) = file else {
+            return Ok(false);
+        };
+        if old_public == public {
+            return Ok(false);
+        }
+
+        let new_key = self.get_s3_key(id, public);
+
+        // 自定义的路径可能与是否公开无关，此时只需修改记录
+        if new_key == old_key {
+            sqlx::query("UPDATE kufang.files SET public = $2, dedup = false WHERE id = $1")
+                .bind(id)
+                .bind(public)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            return Ok(true);
+        }
+
+        let status = self
+            .bucket()
+            .copy_object_internal(&old_key, &new_key)
+            .await?;
+        if !(200..300).contains(&status) {
+            return Err(s3::error::S3Error::HttpFailWithBody(status, String::new()).into());
+        }
+
+        sqlx::query(
+            "UPDATE kufang.files SET s3_key = $2, public = $3, dedup = false WHERE id = $1",
+        )
+        .bind(id)
+        .bind(&new_key)
+        .bind(public)
+        .execute(&mut *tx)
+        .await?;
+
+        // 旧对象删除失败时回滚，并尽量清理新对象，避免私有文件在公开路径下留有副本
+        let deleted = match self.bucket().delete_object(&old_key).await {
+            Ok(resp) if resp.status_code() == 404 => Ok(()),
+            Ok(resp) => check_s3_status(resp),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = deleted {
+            let _ = self.bucket().delete_object(&new_key).await;
+            return Err(e.into());
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    /// 删除引用计数 <= 0 的文件，同时删除 S3 对象与数据库记录
+    /// 返回是否删除，文件仍被引用或不存在时返回 `false`
+    ///
+    /// 先删除数据库记录再删除 S3 对象，S3 删除失败时只会留下没有记录的孤儿对象
+    pub async fn delete_file(&self, id: Uuid) -> Result<bool, Error> {
+        let key = sqlx::query_scalar::<_, String>(
+            "DELETE FROM kufang.files WHERE id = $1 AND ref_count <= 0 RETURNING s3_key",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(key) = key else {
+            return Ok(false);
+        };
+
+        // 记录已删除，不在事务中等待 S3；孤儿对象不影响使用，忽略删除失败
+        let _ = self.bucket().delete_object(&key).await;
+
+        Ok(true)
+    }
+
+    /// 删除引用计数 <= 0 且创建时间早于 `older_than` 之前的文件，返回删除的文件数
+    /// 文件没有单独的创建时间列，创建时间取自 uuid v7 的时间戳
+    pub async fn collect_garbage(&self, older_than: time::Duration) -> Result<u64, Error> {
+        let cutoff = time::OffsetDateTime::now_utc() - older_than;
+        let cutoff_id = uuid::Builder::from_unix_timestamp_millis(
+            (cutoff.unix_timestamp_nanos() / 1_000_000) as u64,
+            &[0; 10],
+        )
+        .into_uuid();
+
+        let ids = sqlx::query_scalar::<_, Uuid>(
+            "SELECT id FROM kufang.files WHERE ref_count <= 0 AND id < $1",
+        )
+        .bind(cutoff_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut deleted = 0;
+        for id in ids {
+            if self.delete_file(id).await? {
+                deleted += 1;
+            }
+        }
+
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 连接 `DATABASE_URL` 指向的数据库并执行迁移，以 `cargo test -- --ignored` 运行
+    pub(crate) async fn test_pool() -> sqlx::PgPool {
+        use sea_orm_migration::{MigratorTrait, sea_orm::SqlxPostgresConnector};
+
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is not set");
+        let pool = sqlx::PgPool::connect(&url).await.unwrap();
+
+        let db = SqlxPostgresConnector::from_sqlx_postgres_pool(pool.clone());
+        migration::Migrator::up(&db, None).await.unwrap();
+
+        pool
+    }
+
+    /// 读完请求后对所有请求返回 200 的 S3 服务，返回服务地址
+    async fn mock_s3() -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut req = Vec::new();
+                    let mut buf = [0; 4096];
+                    loop {
+                        let n = stream.read(&mut buf).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        req.extend_from_slice(&buf[..n]);
+
+                        let Some(end) = req.windows(4).position(|w| w == b"\r\n\r\n") else {
+                            continue;
+                        };
+                        let head = String::from_utf8_lossy(&req[..end]).to_lowercase();
+                        let len = head
+                            .lines()
+                            .find_map(|l| l.strip_prefix("content-length:"))
+                            .map_or(0, |v| v.trim().parse().unwrap());
+                        if req.len() >= end + 4 + len {
+                            break;
+                        }
+                    }
+
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        )
+                        .await;
+                });
+            }
+        });
+
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn upload_file_dedups_identical_bytes() {
+        let kufang = Kufang::builder()
+            .bucket("bucket", "region", mock_s3().await)
+            .credentials("key", "secret")
+            .pool(test_pool().await)
+            .key_prefix("test")
+            .build()
+            .unwrap();
+        // 模拟服务没有按 bucket 区分的域名，改为路径风格访问
+        let bucket = kufang.bucket().with_path_style();
+        *kufang.bucket.write().unwrap() = Arc::from(bucket);
+
+        let file: [u8; 32] = rand::random();
+        let mime = "application/octet-stream";
+
+        let first = kufang
+            .upload_file(&file, mime, Some("a.bin"), false, false)
+            .await
+            .unwrap();
+        let second = kufang
+            .upload_file(&file, mime, Some("b.bin"), false, false)
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+
+        let record = kufang.get_file(first).await.unwrap().unwrap();
+        assert_eq!(record.ref_count, 2);
+        assert_eq!(record.filename.as_deref(), Some("a.bin"));
+
+        let forced = kufang
+            .upload_file(&file, mime, None, false, true)
+            .await
+            .unwrap();
+        assert_ne!(forced, first);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn upload_file_with_id_rejects_registered_id() {
+        let kufang = Kufang::builder()
+            .bucket("bucket", "region", mock_s3().await)
+            .credentials("key", "secret")
+            .pool(test_pool().await)
+            .key_prefix("test")
+            .build()
+            .unwrap();
+        let bucket = kufang.bucket().with_path_style();
+        *kufang.bucket.write().unwrap() = Arc::from(bucket);
+
+        let id = Uuid::now_v7();
+        let mime = "application/octet-stream";
+
+        kufang
+            .upload_file_with_id(id, b"first", mime, None, false, None)
+            .await
+            .unwrap();
+
+        let err = kufang
+            .upload_file_with_id(id, b"second", mime, None, false, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::FileConflict(i) if i == id));
+
+        let record = kufang.get_file(id).await.unwrap().unwrap();
+        assert_eq!(record.size, 5);
+    }
+
+    #[test]
+    fn content_disposition_encodes_non_ascii() {
+        assert_eq!(
+            content_disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            content_disposition("讲义 1.pdf"),
+            "attachment; filename=\"__ 1.pdf\"; filename*=UTF-8''%E8%AE%B2%E4%B9%89%201.pdf"
+        );
+    }
 }
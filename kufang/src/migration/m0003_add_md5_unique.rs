@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        // 只约束参与去重的文件，强制上传、指定 id 上传与直传的文件允许内容重复
+        // 已有记录不参与去重，避免历史重复数据导致建索引失败
+        db.execute_unprepared(
+            "
+            ALTER TABLE kufang.files ADD COLUMN dedup boolean NOT NULL DEFAULT false;
+
+            CREATE UNIQUE INDEX files_md5_public_dedup_idx
+                ON kufang.files (md5, public) WHERE dedup;
+            ",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared(
+            "
+            DROP INDEX kufang.files_md5_public_dedup_idx;
+
+            ALTER TABLE kufang.files DROP COLUMN dedup;
+            ",
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub(super) struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        // 相同内容但声明类型不同的文件分别保存，对象的 Content-Type 与记录一致
+        db.execute_unprepared(
+            "
+            DROP INDEX kufang.files_md5_public_dedup_idx;
+
+            CREATE UNIQUE INDEX files_md5_public_mime_dedup_idx
+                ON kufang.files (md5, public, mime) WHERE dedup;
+            ",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared(
+            "
+            DROP INDEX kufang.files_md5_public_mime_dedup_idx;
+
+            CREATE UNIQUE INDEX files_md5_public_dedup_idx
+                ON kufang.files (md5, public) WHERE dedup;
+            ",
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 use sea_orm_migration::prelude::*;
 
 mod m0001_create_table;
+mod m0002_add_filename;
+mod m0003_add_md5_unique;
+mod m0004_dedup_by_mime;
 
 pub struct Migrator;
 
@@ -10,6 +13,11 @@ impl MigratorTrait for Migrator {
     }
 
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m0001_create_table::Migration)]
+        vec![
+            Box::new(m0001_create_table::Migration),
+            Box::new(m0002_add_filename::Migration),
+            Box::new(m0003_add_md5_unique::Migration),
+            Box::new(m0004_dedup_by_mime::Migration),
+        ]
     }
 }
@@ -1,15 +1,54 @@
 use http::HeaderValue;
-use regex::{Captures, Regex};
-use rsa::{RsaPublicKey, pkcs1v15, pkcs8::DecodePublicKey as _, signature::Verifier as _};
 use s3::{PostPolicy, PostPolicyField, PostPolicyValue};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use uuid::Uuid;
 
 use crate::Kufang;
 
+#[derive(Debug, thiserror::Error)]
+pub enum CallbackError {
+    /// 公钥地址不在阿里云的公钥域名下，回调可能是伪造的
+    #[error("untrusted public key url: {0}")]
+    UntrustedPublicKeyUrl(String),
+
+    #[error("failed to fetch callback public key: {0}")]
+    PublicKeyFetch(#[from] reqwest::Error),
+
+    /// 获取公钥超时或服务端出错，重试后仍失败
+    #[error("callback public key unavailable after {attempts} attempts: {source}")]
+    PublicKeyUnavailable {
+        attempts: u32,
+        source: reqwest::Error,
+    },
+
+    #[error("invalid callback signature")]
+    SignatureInvalid,
+
+    #[error("invalid callback body: {0}")]
+    BadBody(#[from] serde_json::Error),
+
+    /// 回调 body 超出构建时设置的 `max_callback_body_size`
+    #[error("callback body of {size} bytes exceeds the limit of {limit} bytes")]
+    PayloadTooLarge { size: usize, limit: usize },
+
+    #[error("invalid callback md5: {0}")]
+    BadMd5(#[from] base64::DecodeError),
+
+    /// 当前存储后端不支持上传回调，应改用 [`Kufang::confirm_post_object`]
+    #[error("storage provider does not support upload callbacks")]
+    Unsupported,
+
+    /// 该 file_id 已登记为其他对象或已被引用，不能覆盖
+    #[error("file {0} is already registered")]
+    FileConflict(Uuid),
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
 #[derive(Debug, Serialize)]
 pub struct PostObjectUrl {
+    pub file_id: Uuid,
     pub url: String,
     pub fields: Vec<(String, String)>,
 }
@@ -17,48 +56,77 @@ pub struct PostObjectUrl {
 impl Kufang {
     pub async fn get_post_object_url(&self, callback_url: &str, public: bool) -> PostObjectUrl {
         let file_id = Uuid::now_v7();
-        let object_key = self.get_s3_key(file_id, public);
 
-        let callback = get_post_object_callback(
+        self.post_object_url(
+            file_id,
+            self.get_s3_key(file_id, public),
             callback_url,
-            Some(vec![
-                ("file_id", file_id.to_string().into()),
-                ("object_key", "${object}".into()),
-                ("mime", "${mimeType}".into()),
-                ("size", "${size}".into()),
-                ("md5_base64", "${contentMd5}".into()),
-                ("public", public.into()),
-            ]),
-        );
+            public,
+        )
+        .await
+    }
 
-        let policy = PostPolicy::new(30)
-            .condition(
-                PostPolicyField::Bucket,
-                PostPolicyValue::Exact(self.bucket.name.as_str().into()),
-            )
-            .expect("bucket policy")
-            .condition(
-                PostPolicyField::Custom("callback".into()),
-                PostPolicyValue::Exact(callback.into()),
-            )
-            .expect("bucket policy")
-            .condition(
-                PostPolicyField::ContentLengthRange,
-                PostPolicyValue::Range(0, 100 * 1024 * 1024), // 100M
-            )
-            .expect("bucket policy")
-            .condition(
-                PostPolicyField::Key,
-                PostPolicyValue::Exact(object_key.into()),
-            )
-            .expect("bucket policy");
+    /// 使用调用方指定的 `file_id`，客户端重试直传时可复用同一个 id 与对象路径，
+    /// 文件被引用前重复的上传回调会更新同一条文件记录而不是产生新的记录
+    /// errors:
+    /// - `file_id` 不是 uuid v7 时返回 [`crate::Error::InvalidFileId`]
+    /// - 该 file_id 已被引用或登记为其他对象时返回 [`crate::Error::FileConflict`]，不会签发 policy
+    pub async fn get_post_object_url_with_id(
+        &self,
+        file_id: Uuid,
+        callback_url: &str,
+        public: bool,
+    ) -> Result<PostObjectUrl, crate::Error> {
+        crate::check_file_id(file_id)?;
 
-        let presigned_post = self.bucket.presign_post(policy).await.unwrap();
+        let object_key = self.get_s3_key(file_id, public);
+
+        // 签发后客户端可以直接覆盖对象内容，因此只允许未被引用且路径相同的记录重试上传
+        let existing = sqlx::query_as::<_, (String, i32)>(
+            "SELECT s3_key, ref_count FROM kufang.files WHERE id = $1",
+        )
+        .bind(file_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some((key, ref_count)) = existing
+            && (ref_count != 0 || key != object_key)
+        {
+            return Err(crate::Error::FileConflict(file_id));
+        }
+
+        Ok(self
+            .post_object_url(file_id, object_key, callback_url, public)
+            .await)
+    }
+
+    async fn post_object_url(
+        &self,
+        file_id: Uuid,
+        object_key: String,
+        callback_url: &str,
+        public: bool,
+    ) -> PostObjectUrl {
+        let conditions =
+            self.storage_provider
+                .post_policy_conditions(callback_url, file_id, public);
+
+        let policy = post_policy(
+            &self.bucket().name,
+            conditions,
+            object_key,
+            self.allowed_mime_prefix(),
+            self.max_upload_size,
+            self.post_policy_expiry_secs,
+        );
+
+        let presigned_post = self.bucket().presign_post(policy).await.unwrap();
 
         let mut fields: Vec<(String, String)> = presigned_post.fields.into_iter().collect();
-        fields.extend(presigned_post.dynamic_fields.into_iter());
+        fields.extend(presigned_post.dynamic_fields);
 
         PostObjectUrl {
+            file_id,
             url: presigned_post.url,
             fields,
         }
@@ -71,14 +139,18 @@ impl Kufang {
         path: &str,
         query: &str,
         body: &str,
-    ) -> Uuid {
-        if verify_callback(pub_key_header, auth_header, path, query, body)
-            .await
-            .is_err()
-        {
-            panic!();
+    ) -> Result<Uuid, CallbackError> {
+        if body.len() > self.max_callback_body_size {
+            return Err(CallbackError::PayloadTooLarge {
+                size: body.len(),
+                limit: self.max_callback_body_size,
+            });
         }
 
+        self.storage_provider
+            .verify_callback(pub_key_header, auth_header, path, query, body)
+            .await?;
+
         #[derive(Deserialize, Debug)]
         struct CallbackBody {
             file_id: Uuid,
@@ -87,20 +159,29 @@ impl Kufang {
             size: i64,
             md5_base64: String,
             public: bool,
+            #[serde(default)]
+            filename: Option<String>,
         }
 
-        let body: CallbackBody =
-            serde_json::from_str(body).expect("body should be valid json string");
+        let body: CallbackBody = serde_json::from_str(body)?;
 
         use base64::prelude::*;
-        let md5 = BASE64_STANDARD
-            .decode(body.md5_base64)
-            .expect("body md5 should be valid base64");
+        let md5 = BASE64_STANDARD.decode(body.md5_base64)?;
 
-        sqlx::query(
+        // 同一 file_id 重试上传时以最后一次上传的对象为准；
+        // 已被引用或对象路径不同的记录不会被覆盖，避免重放或伪造的回调改写正在使用的文件
+        let stored = sqlx::query_scalar::<_, Uuid>(
             "
-            INSERT INTO kufang.files (id, s3_key, size, md5, mime, ref_count, public)
-            VALUES ($1, $2, $3, $4, $5, 0, $6)
+            INSERT INTO kufang.files (id, s3_key, size, md5, mime, ref_count, public, filename)
+            VALUES ($1, $2, $3, $4, $5, 0, $6, $7)
+            ON CONFLICT (id) DO UPDATE SET
+                size = EXCLUDED.size,
+                md5 = EXCLUDED.md5,
+                mime = EXCLUDED.mime,
+                public = EXCLUDED.public,
+                filename = COALESCE(EXCLUDED.filename, kufang.files.filename)
+            WHERE kufang.files.ref_count = 0 AND kufang.files.s3_key = EXCLUDED.s3_key
+            RETURNING id
             ",
         )
         .bind(body.file_id)
@@ -109,79 +190,251 @@ impl Kufang {
         .bind(md5)
         .bind(body.mime)
         .bind(body.public)
-        .execute(&self.pool)
-        .await
-        .unwrap();
+        .bind(body.filename.filter(|f| !f.is_empty()))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        stored.ok_or(CallbackError::FileConflict(body.file_id))
+    }
+
+    /// 不支持上传回调的存储后端（如 [`crate::GenericS3`]）在客户端直传完成后调用，
+    /// 通过 HEAD 确认对象存在并登记文件，对象不存在时返回 `None`
+    /// errors:
+    /// - `file_id` 不是 uuid v7 时返回 [`crate::Error::InvalidFileId`]
+    /// - 该 file_id 已被引用或登记为其他对象时返回 [`crate::Error::FileConflict`]
+    pub async fn confirm_post_object(
+        &self,
+        file_id: Uuid,
+        filename: Option<&str>,
+        public: bool,
+    ) -> Result<Option<Uuid>, crate::Error> {
+        crate::check_file_id(file_id)?;
+
+        let key = self.get_s3_key(file_id, public);
+
+        let (head, status) = self.bucket().head_object(&key).await?;
+        if status == 404 {
+            return Ok(None);
+        }
+        if !(200..300).contains(&status) {
+            return Err(s3::error::S3Error::HttpFailWithBody(status, String::new()).into());
+        }
+
+        // 单段上传的 ETag 即内容的 md5，分段上传时无法得到 md5，记为空
+        let md5 = head
+            .e_tag
+            .as_deref()
+            .and_then(|e_tag| parse_md5_hex(e_tag.trim_matches('"')))
+            .unwrap_or_default();
+
+        // 与上传回调一样，重复确认同一个 file_id 时只更新未被引用且路径相同的记录
+        let stored = sqlx::query_scalar::<_, Uuid>(
+            "
+            INSERT INTO kufang.files (id, s3_key, size, md5, mime, ref_count, public, filename)
+            VALUES ($1, $2, $3, $4, $5, 0, $6, $7)
+            ON CONFLICT (id) DO UPDATE SET
+                size = EXCLUDED.size,
+                md5 = EXCLUDED.md5,
+                mime = EXCLUDED.mime,
+                public = EXCLUDED.public,
+                filename = COALESCE(EXCLUDED.filename, kufang.files.filename)
+            WHERE kufang.files.ref_count = 0 AND kufang.files.s3_key = EXCLUDED.s3_key
+            RETURNING id
+            ",
+        )
+        .bind(file_id)
+        .bind(key)
+        .bind(head.content_length.unwrap_or_default())
+        .bind(md5)
+        .bind(
+            head.content_type
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+        )
+        .bind(public)
+        .bind(filename)
+        .fetch_optional(&self.pool)
+        .await?;
 
-        body.file_id
+        match stored {
+            Some(id) => Ok(Some(id)),
+            None => Err(crate::Error::FileConflict(file_id)),
+        }
     }
 }
 
-/// https://help.aliyun.com/zh/oss/developer-reference/callback
-fn get_post_object_callback(url: &str, body: Option<Vec<(&str, serde_json::Value)>>) -> String {
-    let callback_body = if let Some(body) = body {
-        serde_json::Map::from_iter(body.into_iter().map(|(k, v)| (k.to_string(), v)))
-    } else {
-        serde_json::Map::new()
-    };
-
-    let callback_body = serde_json::Value::Object(callback_body).to_string();
-
-    let regex = Regex::new(r#""\$\{[\w\.:]+\}""#).expect("regex correct");
-    let callback_body = regex.replace_all(&callback_body, |c: &Captures| {
-        let mut full = c.get(0).expect("regex matched").as_str().to_string();
-        full.pop();
-        full.remove(0);
-
-        full
-    });
-
-    let string = json!({
-        "callbackUrl": url,
-        "callbackBody": callback_body,
-        "callbackBodyType": "application/json"
-    })
-    .to_string();
-
-    use base64::prelude::*;
-    BASE64_STANDARD.encode(string)
+fn post_policy(
+    bucket_name: &str,
+    conditions: Vec<(String, String)>,
+    object_key: String,
+    mime_prefix: Option<&str>,
+    max_size: u32,
+    expiry_secs: u32,
+) -> PostPolicy<'static> {
+    let mut policy = PostPolicy::new(expiry_secs)
+        .condition(
+            PostPolicyField::Bucket,
+            PostPolicyValue::Exact(bucket_name.to_string().into()),
+        )
+        .expect("bucket policy")
+        .condition(
+            PostPolicyField::ContentLengthRange,
+            PostPolicyValue::Range(0, max_size),
+        )
+        .expect("bucket policy")
+        .condition(
+            PostPolicyField::Key,
+            PostPolicyValue::Exact(object_key.into()),
+        )
+        .expect("bucket policy");
+
+    if let Some(prefix) = mime_prefix {
+        policy = policy
+            .condition(
+                PostPolicyField::ContentType,
+                PostPolicyValue::StartsWith(prefix.to_string().into()),
+            )
+            .expect("bucket policy");
+    }
+
+    for (name, value) in conditions {
+        policy = policy
+            .condition(
+                PostPolicyField::Custom(name.into()),
+                PostPolicyValue::Exact(value.into()),
+            )
+            .expect("bucket policy");
+    }
+
+    policy
+}
+
+fn parse_md5_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() != 32 {
+        return None;
+    }
+
+    (0..32)
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
 }
 
-async fn verify_callback(
-    pub_key_header: &HeaderValue,
-    auth_header: &HeaderValue,
-    path: &str,
-    query: &str,
-    body: &str,
-) -> Result<(), ()> {
-    let signed_string = format!("{path}{query}\n{body}");
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
 
-    use base64::prelude::*;
+    use super::*;
 
-    let pub_key_header = BASE64_STANDARD
-        .decode(pub_key_header.as_bytes())
-        .map_err(|_| ())?;
+    #[test]
+    fn post_policy_uses_configured_limits() {
+        let before = time::OffsetDateTime::now_utc();
+        let policy = post_policy("bucket", vec![], "key".into(), Some("image/"), 1024, 600);
+        let policy = serde_json::to_value(&policy).unwrap();
 
-    let pub_key_url = std::str::from_utf8(&pub_key_header).map_err(|_| ())?;
+        let conditions = policy["conditions"].as_array().unwrap();
+        assert!(conditions.contains(&json!(["content-length-range", 0, 1024])));
+        assert!(conditions.contains(&json!({ "key": "key" })));
+        assert!(conditions.contains(&json!(["starts-with", "$Content-Type", "image/"])));
 
-    if !pub_key_url.starts_with("http://gosspublic.alicdn.com/")
-        && !pub_key_url.starts_with("https://gosspublic.alicdn.com/")
-    {
-        return Err(());
+        let expiration = time::OffsetDateTime::parse(
+            policy["expiration"].as_str().unwrap(),
+            &time::format_description::well_known::Rfc3339,
+        )
+        .unwrap();
+        assert!(expiration - before >= time::Duration::seconds(599));
+        assert!(expiration - before <= time::Duration::seconds(601));
+    }
+
+    #[test]
+    fn parse_etag_md5() {
+        assert_eq!(
+            parse_md5_hex("5d41402abc4b2a76b9719d911017c592"),
+            Some(vec![
+                0x5d, 0x41, 0x40, 0x2a, 0xbc, 0x4b, 0x2a, 0x76, 0xb9, 0x71, 0x9d, 0x91, 0x10, 0x17,
+                0xc5, 0x92
+            ])
+        );
+        assert_eq!(parse_md5_hex("5d41402abc4b2a76b9719d911017c592-2"), None);
+    }
+
+    #[tokio::test]
+    async fn callback_rejects_oversized_body() {
+        let kufang = Kufang::builder()
+            .bucket("bucket", "region", "http://localhost")
+            .credentials("key", "secret")
+            .pool(sqlx::PgPool::connect_lazy("postgres://localhost/kufang").unwrap())
+            .key_prefix("files")
+            .max_callback_body_size(4)
+            .build()
+            .unwrap();
+
+        let header = HeaderValue::from_static("");
+        let err = kufang
+            .handle_post_object_callback(&header, &header, "/", "", "12345")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            CallbackError::PayloadTooLarge { size: 5, limit: 4 }
+        ));
     }
 
-    let res = reqwest::get(pub_key_url).await.map_err(|_| ())?;
-    let pub_key = res.text().await.map_err(|_| ())?;
+    #[tokio::test]
+    async fn post_object_url_rejects_non_v7_id() {
+        let kufang = Kufang::builder()
+            .bucket("bucket", "region", "http://localhost")
+            .credentials("key", "secret")
+            .pool(sqlx::PgPool::connect_lazy("postgres://localhost/kufang").unwrap())
+            .key_prefix("files")
+            .build()
+            .unwrap();
 
-    let pub_key = RsaPublicKey::from_public_key_pem(&pub_key).map_err(|_| ())?;
-    let verify_key = pkcs1v15::VerifyingKey::<md5::Md5>::new(pub_key);
+        let id = Uuid::from_u128(0x0123_4567_89ab_4def_8123_4567_89ab_cdef);
+        let err = kufang
+            .get_post_object_url_with_id(id, "https://example.com/callback", false)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, crate::Error::InvalidFileId(i) if i == id));
+    }
 
-    let sign = BASE64_STANDARD
-        .decode(auth_header.as_bytes())
-        .map_err(|_| ())?;
-    let sign: pkcs1v15::Signature = sign.as_slice().try_into().map_err(|_| ())?;
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn post_object_url_rejects_referenced_file() {
+        let kufang = Kufang::builder()
+            .bucket("bucket", "region", "http://localhost")
+            .credentials("key", "secret")
+            .pool(crate::tests::test_pool().await)
+            .key_prefix("files")
+            .build()
+            .unwrap();
 
-    verify_key
-        .verify(signed_string.as_bytes(), &sign)
-        .map_err(|_| ())
+        let id = Uuid::now_v7();
+        sqlx::query(
+            "
+            INSERT INTO kufang.files (id, s3_key, size, md5, mime, ref_count, public)
+            VALUES ($1, $2, 0, '', 'text/plain', 0, false)
+            ",
+        )
+        .bind(id)
+        .bind(kufang.get_s3_key(id, false))
+        .execute(&kufang.pool)
+        .await
+        .unwrap();
+
+        // 未被引用时允许以同一个 id 重试上传
+        kufang
+            .get_post_object_url_with_id(id, "https://example.com/callback", false)
+            .await
+            .unwrap();
+
+        kufang.increment_ref(id).await.unwrap();
+
+        let err = kufang
+            .get_post_object_url_with_id(id, "https://example.com/callback", false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, crate::Error::FileConflict(i) if i == id));
+    }
 }
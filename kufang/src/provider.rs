@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use http::HeaderValue;
+use regex::{Captures, Regex};
+use rsa::{RsaPublicKey, pkcs1v15, pkcs8::DecodePublicKey as _, signature::Verifier as _};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::CallbackError;
+
+/// 存储后端在直传流程上的差异：policy 中的附加条件，以及上传回调的校验方式
+#[async_trait::async_trait]
+pub trait StorageProvider: Send + Sync {
+    /// 直传 policy 中附加的自定义条件，如 OSS 的上传回调
+    fn post_policy_conditions(
+        &self,
+        callback_url: &str,
+        file_id: Uuid,
+        public: bool,
+    ) -> Vec<(String, String)>;
+
+    /// 校验上传回调请求的签名
+    async fn verify_callback(
+        &self,
+        pub_key_header: &HeaderValue,
+        auth_header: &HeaderValue,
+        path: &str,
+        query: &str,
+        body: &str,
+    ) -> Result<(), CallbackError>;
+}
+
+/// 阿里云 OSS，上传完成后由 OSS 回调业务方，回调使用 OSS 公钥签名
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AliyunOss;
+
+#[async_trait::async_trait]
+impl StorageProvider for AliyunOss {
+    fn post_policy_conditions(
+        &self,
+        callback_url: &str,
+        file_id: Uuid,
+        public: bool,
+    ) -> Vec<(String, String)> {
+        let callback = get_post_object_callback(
+            callback_url,
+            Some(vec![
+                ("file_id", file_id.to_string().into()),
+                ("object_key", "${object}".into()),
+                ("mime", "${mimeType}".into()),
+                ("size", "${size}".into()),
+                ("md5_base64", "${contentMd5}".into()),
+                ("public", public.into()),
+                // 客户端通过 `x:filename` 表单字段传入原始文件名
+                ("filename", "${x:filename}".into()),
+            ]),
+        );
+
+        vec![("callback".to_string(), callback)]
+    }
+
+    async fn verify_callback(
+        &self,
+        pub_key_header: &HeaderValue,
+        auth_header: &HeaderValue,
+        path: &str,
+        query: &str,
+        body: &str,
+    ) -> Result<(), CallbackError> {
+        verify_callback(pub_key_header, auth_header, path, query, body).await
+    }
+}
+
+/// AWS S3 / MinIO 等通用 S3 存储，不支持上传回调
+/// 客户端上传完成后需调用 [`crate::Kufang::confirm_post_object`] 通过 HEAD 确认并登记文件
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenericS3;
+
+#[async_trait::async_trait]
+impl StorageProvider for GenericS3 {
+    fn post_policy_conditions(
+        &self,
+        _callback_url: &str,
+        _file_id: Uuid,
+        _public: bool,
+    ) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    async fn verify_callback(
+        &self,
+        _pub_key_header: &HeaderValue,
+        _auth_header: &HeaderValue,
+        _path: &str,
+        _query: &str,
+        _body: &str,
+    ) -> Result<(), CallbackError> {
+        Err(CallbackError::Unsupported)
+    }
+}
+
+/// https://help.aliyun.com/zh/oss/developer-reference/callback
+fn get_post_object_callback(url: &str, body: Option<Vec<(&str, serde_json::Value)>>) -> String {
+    let callback_body = if let Some(body) = body {
+        serde_json::Map::from_iter(body.into_iter().map(|(k, v)| (k.to_string(), v)))
+    } else {
+        serde_json::Map::new()
+    };
+
+    let callback_body = serde_json::Value::Object(callback_body).to_string();
+
+    let regex = Regex::new(r#""\$\{[\w\.:]+\}""#).expect("regex correct");
+    let callback_body = regex.replace_all(&callback_body, |c: &Captures| {
+        let mut full = c.get(0).expect("regex matched").as_str().to_string();
+        full.pop();
+        full.remove(0);
+
+        full
+    });
+
+    let string = json!({
+        "callbackUrl": url,
+        "callbackBody": callback_body,
+        "callbackBodyType": "application/json"
+    })
+    .to_string();
+
+    use base64::prelude::*;
+    BASE64_STANDARD.encode(string)
+}
+
+/// OSS 回调公钥按地址缓存，地址中包含公钥的 id，同一地址的公钥不会变化，因此进程内共享
+static PUBLIC_KEYS: LazyLock<Mutex<HashMap<String, RsaPublicKey>>> =
+    LazyLock::new(Default::default);
+
+/// 获取公钥的请求带超时，避免公钥服务异常时回调请求长时间挂起
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .expect("reqwest client")
+});
+
+const FETCH_ATTEMPTS: u32 = 3;
+
+async fn fetch_public_key(url: &str) -> Result<RsaPublicKey, CallbackError> {
+    if let Some(key) = PUBLIC_KEYS
+        .lock()
+        .expect("public key cache poisoned")
+        .get(url)
+    {
+        return Ok(key.clone());
+    }
+
+    let pem = fetch_pem(&HTTP_CLIENT, url, FETCH_ATTEMPTS).await?;
+
+    let key =
+        RsaPublicKey::from_public_key_pem(&pem).map_err(|_| CallbackError::SignatureInvalid)?;
+
+    PUBLIC_KEYS
+        .lock()
+        .expect("public key cache poisoned")
+        .insert(url.to_string(), key.clone());
+
+    Ok(key)
+}
+
+/// 超时、连接失败与 5xx 时重试，4xx 直接返回 [`CallbackError::PublicKeyFetch`]
+async fn fetch_pem(
+    client: &reqwest::Client,
+    url: &str,
+    attempts: u32,
+) -> Result<String, CallbackError> {
+    let mut attempt = 1;
+
+    loop {
+        let res = async {
+            client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await
+        }
+        .await;
+
+        let err = match res {
+            Ok(pem) => return Ok(pem),
+            Err(e) if e.status().is_some_and(|s| s.is_client_error()) => {
+                return Err(CallbackError::PublicKeyFetch(e));
+            }
+            Err(e) => e,
+        };
+
+        if attempt >= attempts {
+            return Err(CallbackError::PublicKeyUnavailable {
+                attempts,
+                source: err,
+            });
+        }
+
+        tokio::time::sleep(Duration::from_millis(200 * attempt as u64)).await;
+        attempt += 1;
+    }
+}
+
+async fn verify_callback(
+    pub_key_header: &HeaderValue,
+    auth_header: &HeaderValue,
+    path: &str,
+    query: &str,
+    body: &str,
+) -> Result<(), CallbackError> {
+    let signed_string = format!("{path}{query}\n{body}");
+
+    use base64::prelude::*;
+
+    let pub_key_header = BASE64_STANDARD
+        .decode(pub_key_header.as_bytes())
+        .map_err(|_| CallbackError::SignatureInvalid)?;
+
+    let pub_key_url =
+        String::from_utf8(pub_key_header).map_err(|_| CallbackError::SignatureInvalid)?;
+
+    if !pub_key_url.starts_with("http://gosspublic.alicdn.com/")
+        && !pub_key_url.starts_with("https://gosspublic.alicdn.com/")
+    {
+        return Err(CallbackError::UntrustedPublicKeyUrl(pub_key_url));
+    }
+
+    let pub_key = fetch_public_key(&pub_key_url).await?;
+    let verify_key = pkcs1v15::VerifyingKey::<md5::Md5>::new(pub_key);
+
+    let sign = BASE64_STANDARD
+        .decode(auth_header.as_bytes())
+        .map_err(|_| CallbackError::SignatureInvalid)?;
+    let sign: pkcs1v15::Signature = sign
+        .as_slice()
+        .try_into()
+        .map_err(|_| CallbackError::SignatureInvalid)?;
+
+    verify_key
+        .verify(signed_string.as_bytes(), &sign)
+        .map_err(|_| CallbackError::SignatureInvalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn verify_callback_rejects_untrusted_public_key_url() {
+        use base64::prelude::*;
+
+        let pub_key = BASE64_STANDARD.encode("https://example.com/key.pem");
+        let pub_key = HeaderValue::from_str(&pub_key).unwrap();
+        let auth = HeaderValue::from_static("c2ln");
+
+        let err = verify_callback(&pub_key, &auth, "/cb", "", "{}")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CallbackError::UntrustedPublicKeyUrl(_)));
+    }
+
+    #[tokio::test]
+    async fn fetch_pem_gives_up_after_retries() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // 接受连接但从不响应
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                conns.push(stream);
+            }
+        });
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+
+        let err = fetch_pem(&client, &format!("http://{addr}/key.pem"), 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            CallbackError::PublicKeyUnavailable { attempts: 2, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify_callback_uses_cached_public_key() {
+        use base64::prelude::*;
+        use rsa::signature::{SignatureEncoding as _, Signer as _};
+
+        let url = "https://gosspublic.alicdn.com/cached.pem";
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        PUBLIC_KEYS
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), key.to_public_key());
+
+        let sign = pkcs1v15::SigningKey::<md5::Md5>::new(key).sign(b"/cb\n{}");
+
+        let pub_key = HeaderValue::from_str(&BASE64_STANDARD.encode(url)).unwrap();
+        let auth = HeaderValue::from_str(&BASE64_STANDARD.encode(sign.to_bytes())).unwrap();
+
+        // 地址不可访问，只有命中缓存才能通过
+        verify_callback(&pub_key, &auth, "/cb", "", "{}")
+            .await
+            .unwrap();
+    }
+}
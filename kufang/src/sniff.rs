@@ -0,0 +1,117 @@
+use crate::Error;
+
+/// 根据文件内容校验声明的类型，见 `KufangBuilder::mime_check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MimeCheck {
+    /// 检测到的类型与声明的不一致时返回 [`Error::MimeMismatch`]
+    Reject,
+    /// 以检测到的类型为准；声明为可识别的类型但内容无法识别时保存为 `application/octet-stream`
+    Override,
+}
+
+/// (类型, 偏移, 魔数)，只收录能从开头几个字节可靠区分的类型
+const SIGNATURES: &[(&str, usize, &[u8])] = &[
+    ("image/png", 0, b"\x89PNG\r\n\x1a\n"),
+    ("image/jpeg", 0, b"\xff\xd8\xff"),
+    ("image/gif", 0, b"GIF87a"),
+    ("image/gif", 0, b"GIF89a"),
+    ("image/webp", 8, b"WEBP"),
+    ("application/pdf", 0, b"%PDF-"),
+    ("application/x-msdownload", 0, b"MZ"),
+    ("application/x-executable", 0, b"\x7fELF"),
+    ("application/x-mach-binary", 0, b"\xcf\xfa\xed\xfe"),
+];
+
+fn sniff(file: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(_, offset, magic)| file.get(*offset..offset + magic.len()) == Some(*magic))
+        .map(|(mime, _, _)| *mime)
+}
+
+/// 去掉参数并统一大小写与常见别名，如 `image/JPG; q=1` -> `image/jpeg`
+fn essence(mime: &str) -> String {
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+
+    match mime.to_ascii_lowercase().as_str() {
+        "image/jpg" | "image/pjpeg" => "image/jpeg".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// 返回应当保存的类型
+pub(crate) fn check_content<'a>(
+    file: &[u8],
+    mime: &'a str,
+    mode: MimeCheck,
+) -> Result<&'a str, Error> {
+    let detected = sniff(file);
+    let declared = essence(mime);
+
+    let matches = match detected {
+        Some(detected) => detected == declared,
+        // 无法识别的内容只在声明为可识别的类型时视为不一致
+        None => !SIGNATURES.iter().any(|(m, _, _)| *m == declared),
+    };
+
+    match (matches, mode) {
+        (true, _) => Ok(mime),
+        (false, MimeCheck::Reject) => Err(Error::MimeMismatch {
+            declared: mime.to_string(),
+            detected,
+        }),
+        (false, MimeCheck::Override) => Ok(detected.unwrap_or("application/octet-stream")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const EXE: &[u8] = b"MZ\x90\0\x03\0\0\0";
+
+    #[test]
+    fn reject_mode_catches_disguised_files() {
+        assert_eq!(
+            check_content(PNG, "image/png", MimeCheck::Reject).unwrap(),
+            "image/png"
+        );
+        assert!(matches!(
+            check_content(EXE, "image/png", MimeCheck::Reject),
+            Err(Error::MimeMismatch {
+                detected: Some("application/x-msdownload"),
+                ..
+            })
+        ));
+        assert!(matches!(
+            check_content(b"hello", "image/png", MimeCheck::Reject),
+            Err(Error::MimeMismatch { detected: None, .. })
+        ));
+        // 无法识别的类型按声明的类型保存
+        assert_eq!(
+            check_content(b"a,b\n1,2", "text/csv", MimeCheck::Reject).unwrap(),
+            "text/csv"
+        );
+        assert_eq!(
+            check_content(b"\xff\xd8\xff\xe0", "image/JPG", MimeCheck::Reject).unwrap(),
+            "image/JPG"
+        );
+    }
+
+    #[test]
+    fn override_mode_uses_detected_type() {
+        assert_eq!(
+            check_content(PNG, "image/jpeg", MimeCheck::Override).unwrap(),
+            "image/png"
+        );
+        assert_eq!(
+            check_content(b"hello", "image/png", MimeCheck::Override).unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            check_content(b"hello", "text/plain", MimeCheck::Override).unwrap(),
+            "text/plain"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use md5::{Digest, Md5};
+use tokio::io::{AsyncRead, ReadBuf};
+use uuid::Uuid;
+
+use crate::{Error, Kufang};
+
+impl Kufang {
+    /// 流式上传文件，较大的文件会分片上传，内存占用与文件大小无关
+    /// md5 与大小在读取时增量计算，`headers` 与 [`Kufang::upload_file_with_id`] 相同
+    /// errors: 与 [`Kufang::upload_file_with_id`] 相同，`id` 已登记时返回 [`Error::FileConflict`]
+    pub async fn upload_file_stream<R: AsyncRead + Unpin>(
+        &self,
+        id: Uuid,
+        reader: R,
+        mime: &str,
+        filename: Option<&str>,
+        public: bool,
+        headers: Option<&HashMap<String, String>>,
+    ) -> Result<(), Error> {
+        self.check_mime(mime)?;
+
+        self.check_new_file_id(id).await?;
+
+        let key = self.get_s3_key(id, public);
+
+        let mut reader = HashingReader::new(reader);
+
+        let resp = self
+            .bucket()
+            .put_object_stream_builder(&key)
+            .with_content_type(mime)
+            .with_headers(crate::object_headers(headers)?)
+            .execute_stream(&mut reader)
+            .await?;
+        if !(200..300).contains(&resp.status_code()) {
+            return Err(
+                s3::error::S3Error::HttpFailWithBody(resp.status_code(), String::new()).into(),
+            );
+        }
+
+        let (size, md5) = reader.finish();
+
+        sqlx::query(
+            "
+            INSERT INTO kufang.files (id, s3_key, size, md5, mime, ref_count, public, filename)
+            VALUES ($1, $2, $3, $4, $5, 0, $6, $7)
+            ",
+        )
+        .bind(id)
+        .bind(key)
+        .bind(size as i64)
+        .bind(md5)
+        .bind(mime)
+        .bind(public)
+        .bind(filename)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// 读取时同时计算 md5 与大小
+struct HashingReader<R> {
+    inner: R,
+    md5: Md5,
+    size: u64,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            md5: Md5::new(),
+            size: 0,
+        }
+    }
+
+    fn finish(self) -> (u64, Vec<u8>) {
+        (self.size, self.md5.finalize().to_vec())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[filled..];
+            this.md5.update(read);
+            this.size += read.len() as u64;
+        }
+
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn hashing_reader_matches_digest() {
+        let data = vec![7u8; 100_000];
+
+        let mut reader = HashingReader::new(data.as_slice());
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+
+        assert_eq!(out, data);
+        assert_eq!(reader.finish(), (100_000, Md5::digest(&data).to_vec()));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL"]
+    async fn upload_stream_rejects_registered_id() {
+        let kufang = Kufang::builder()
+            .bucket("bucket", "region", "http://localhost")
+            .credentials("key", "secret")
+            .pool(crate::tests::test_pool().await)
+            .key_prefix("test")
+            .build()
+            .unwrap();
+
+        let id = Uuid::now_v7();
+        sqlx::query(
+            "
+            INSERT INTO kufang.files (id, s3_key, size, md5, mime, ref_count, public)
+            VALUES ($1, 'test/existing', 0, '', 'text/plain', 1, false)
+            ",
+        )
+        .bind(id)
+        .execute(&kufang.pool)
+        .await
+        .unwrap();
+
+        let err = kufang
+            .upload_file_stream(id, b"data".as_slice(), "text/plain", None, false, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::FileConflict(i) if i == id));
+    }
+}